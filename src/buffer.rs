@@ -0,0 +1,237 @@
+//! The buffers of the written content.
+
+use std::{
+    cmp,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+use tempfile::NamedTempFile;
+
+/// The buffered content of an opened file.
+#[derive(Debug)]
+pub enum Buffer {
+    /// The content held in memory.
+    Mem(Vec<u8>),
+    /// The content spilled to a temporary file, which is removed on drop.
+    File(NamedTempFile),
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Buffer::Mem(vec![])
+    }
+}
+
+impl Buffer {
+    pub fn len(&self) -> u64 {
+        match self {
+            Buffer::Mem(content) => content.len() as u64,
+            Buffer::File(file) => file.as_file().metadata().map_or(0, |m| m.len()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the content if it is held in memory.
+    pub fn as_mem(&self) -> Option<&[u8]> {
+        match self {
+            Buffer::Mem(content) => Some(content),
+            Buffer::File(..) => None,
+        }
+    }
+
+    /// Move the content held in memory into a temporary file created in `dir`.
+    pub fn spill(&mut self, dir: &Path) -> io::Result<()> {
+        if let Buffer::Mem(content) = self {
+            let mut file = NamedTempFile::new_in(dir)?;
+            file.write_all(content)?;
+            *self = Buffer::File(file);
+        }
+        Ok(())
+    }
+
+    /// Write the data at `offset`, extending the content if needed.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        match self {
+            Buffer::Mem(content) => {
+                if (content.len() as u64) < end {
+                    content.resize(end as usize, 0);
+                }
+                content[offset as usize..end as usize].copy_from_slice(data);
+            }
+            Buffer::File(file) => {
+                let file = file.as_file_mut();
+                if file.metadata()?.len() < end {
+                    file.set_len(end)?;
+                }
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        match self {
+            Buffer::Mem(content) => content.resize(size as usize, 0),
+            Buffer::File(file) => file.as_file().set_len(size)?,
+        }
+        Ok(())
+    }
+
+    /// Read the whole content.
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Buffer::Mem(content) => Ok(content.clone()),
+            Buffer::File(file) => {
+                let mut content = vec![];
+                file.reopen()?.read_to_end(&mut content)?;
+                Ok(content)
+            }
+        }
+    }
+
+    /// Read the whole content, removing the temporary file.
+    pub fn into_vec(self) -> io::Result<Vec<u8>> {
+        match self {
+            Buffer::Mem(content) => Ok(content),
+            Buffer::File(..) => self.to_vec(),
+        }
+    }
+}
+
+/// The ranges of the buffer written so far, kept sorted and merged.
+#[derive(Clone, Debug, Default)]
+pub struct Ranges(Vec<Range<u64>>);
+
+impl Ranges {
+    /// Add the written range, merging it with the overlapping or adjacent ones.
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.start >= range.end {
+            return;
+        }
+        let mut merged = range;
+        self.0.retain(|r| {
+            if r.start <= merged.end && merged.start <= r.end {
+                merged.start = cmp::min(merged.start, r.start);
+                merged.end = cmp::max(merged.end, r.end);
+                false
+            } else {
+                true
+            }
+        });
+        let pos = self
+            .0
+            .iter()
+            .position(|r| r.start > merged.start)
+            .unwrap_or(self.0.len());
+        self.0.insert(pos, merged);
+    }
+
+    /// Drop the ranges beyond `len`, as the buffer is truncated.
+    pub fn truncate(&mut self, len: u64) {
+        self.0.retain(|r| r.start < len);
+        if let Some(last) = self.0.last_mut() {
+            last.end = cmp::min(last.end, len);
+        }
+    }
+
+    /// Return the length written from the start without a gap,
+    /// or `None` if some range before the last one is never written.
+    pub fn contiguous_len(&self) -> Option<u64> {
+        match &self.0[..] {
+            [] => Some(0),
+            [range] if range.start == 0 => Some(range.end),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The ranges are written as the pairs, since clippy takes `[0..n]` for a mistake.
+    fn ranges(pairs: &[(u64, u64)]) -> Ranges {
+        let mut ranges = Ranges::default();
+        for &(start, end) in pairs {
+            ranges.insert(start..end);
+        }
+        ranges
+    }
+
+    fn pairs(ranges: &Ranges) -> Vec<(u64, u64)> {
+        ranges.0.iter().map(|r| (r.start, r.end)).collect()
+    }
+
+    #[test]
+    fn insert_ranges() {
+        type Case = (&'static [(u64, u64)], &'static [(u64, u64)]);
+        let cases: &[Case] = &[
+            // overlapping
+            (&[(0, 5), (3, 8)], &[(0, 8)]),
+            (&[(2, 4), (0, 10)], &[(0, 10)]),
+            // adjacent
+            (&[(0, 5), (5, 8)], &[(0, 8)]),
+            // out of order
+            (&[(10, 12), (0, 3)], &[(0, 3), (10, 12)]),
+            (&[(10, 12), (0, 3), (3, 10)], &[(0, 12)]),
+            (&[(6, 8), (0, 2), (3, 4)], &[(0, 2), (3, 4), (6, 8)]),
+            // empty
+            (&[(4, 4)], &[]),
+            (&[(0, 2), (5, 3)], &[(0, 2)]),
+        ];
+        for (inserted, expected) in cases {
+            assert_eq!(pairs(&ranges(inserted)), *expected, "{:?}", inserted);
+        }
+    }
+
+    #[test]
+    fn truncate_ranges() {
+        let cases: &[(u64, &[(u64, u64)])] = &[
+            (12, &[(0, 3), (5, 10)]),
+            (7, &[(0, 3), (5, 7)]),
+            (5, &[(0, 3)]),
+            (4, &[(0, 3)]),
+            (2, &[(0, 2)]),
+            (0, &[]),
+        ];
+        for (len, expected) in cases {
+            let mut ranges = ranges(&[(0, 3), (5, 10)]);
+            ranges.truncate(*len);
+            assert_eq!(pairs(&ranges), *expected, "{}", len);
+        }
+    }
+
+    #[test]
+    fn contiguous_len() {
+        assert_eq!(ranges(&[]).contiguous_len(), Some(0));
+        assert_eq!(ranges(&[(0, 5)]).contiguous_len(), Some(5));
+        assert_eq!(ranges(&[(2, 5)]).contiguous_len(), None);
+        assert_eq!(ranges(&[(0, 2), (3, 5)]).contiguous_len(), None);
+    }
+
+    #[test]
+    fn spilled_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut buffer = Buffer::Mem(b"Hello".to_vec());
+        buffer.spill(dir.path()).unwrap();
+        assert!(buffer.as_mem().is_none());
+        assert_eq!(buffer.len(), 5);
+
+        buffer.write_at(5, b", world").unwrap();
+        assert_eq!(buffer.to_vec().unwrap(), b"Hello, world");
+        // The gap is filled with the NUL bytes, as in memory.
+        buffer.write_at(13, b"!").unwrap();
+        assert_eq!(buffer.to_vec().unwrap(), b"Hello, world\0!");
+        buffer.write_at(0, b"J").unwrap();
+        assert_eq!(buffer.len(), 14);
+
+        buffer.set_len(5).unwrap();
+        assert_eq!(buffer.into_vec().unwrap(), b"Jello");
+    }
+}
@@ -0,0 +1,508 @@
+//! Loading the configuration.
+
+use crate::CountMode;
+use serde::Deserialize;
+use std::{
+    env, fs, io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+/// The credentials used to access Twitter API.
+///
+/// The tokens are built once on creation, and borrowed by every API call.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub consumer_key: String,
+    pub consumer_secret: String,
+    pub access_token: String,
+    pub access_token_secret: String,
+    token: egg_mode::Token,
+    /// The app-only bearer token, used by the read endpoints when the
+    /// user context is unavailable.
+    bearer: Option<egg_mode::Token>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::new(
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            None,
+        )
+    }
+}
+
+impl Credentials {
+    /// Create the credentials, building the tokens from the keys.
+    pub fn new(
+        consumer_key: String,
+        consumer_secret: String,
+        access_token: String,
+        access_token_secret: String,
+        bearer_token: Option<String>,
+    ) -> Self {
+        let token = egg_mode::Token::Access {
+            consumer: egg_mode::KeyPair::new(consumer_key.clone(), consumer_secret.clone()),
+            access: egg_mode::KeyPair::new(access_token.clone(), access_token_secret.clone()),
+        };
+        Self {
+            consumer_key,
+            consumer_secret,
+            access_token,
+            access_token_secret,
+            token,
+            bearer: bearer_token.map(egg_mode::Token::Bearer),
+        }
+    }
+
+    /// Return whether the keys of the user context are available,
+    /// which are required to post the statuses.
+    pub fn can_post(&self) -> bool {
+        !self.consumer_key.is_empty()
+            && !self.consumer_secret.is_empty()
+            && !self.access_token.is_empty()
+            && !self.access_token_secret.is_empty()
+    }
+
+    /// Return whether the bearer token is given.
+    pub fn has_bearer(&self) -> bool {
+        self.bearer.is_some()
+    }
+
+    /// Return the token for calling the read endpoints, which is
+    /// the bearer token if the user context is unavailable.
+    pub fn read_token(&self) -> &egg_mode::Token {
+        match self.bearer {
+            Some(ref bearer) if !self.can_post() => bearer,
+            _ => &self.token,
+        }
+    }
+
+    /// Return the access token for calling Twitter API.
+    pub fn token(&self) -> &egg_mode::Token {
+        &self.token
+    }
+}
+
+/// The default maximum size of the buffer of the status files.
+const DEFAULT_MAX_BUFFER: u64 = 1024 * 1024;
+
+/// The backend which the statuses are posted to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Twitter,
+    Mastodon,
+    Mock,
+}
+
+impl Backend {
+    /// Return the name set by `TWEET_FS_BACKEND`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Twitter => "twitter",
+            Backend::Mastodon => "mastodon",
+            Backend::Mock => "mock",
+        }
+    }
+}
+
+/// The behavior of the filesystem, configured by the `TWEET_FS_*` variables.
+///
+/// The variables read before the environment file is loaded (the log format
+/// and file, and the path of the environment file itself) are not included.
+#[derive(Debug)]
+pub struct Config {
+    pub backend: Backend,
+    /// The source label, which is validated but has no effect.
+    pub source: Option<String>,
+    pub allow_other: bool,
+    pub allow_root: bool,
+    pub thread_mode: bool,
+    /// Whether the chunks of a thread are numbered, e.g. " (1/3)".
+    pub thread_numbering: bool,
+    /// Whether each line is posted as a standalone tweet.
+    pub lines_mode: bool,
+    /// Whether the long-form statuses are posted, where the length limit is raised.
+    pub longform: bool,
+    /// How the length of a status is counted against the limit.
+    pub count_mode: CountMode,
+    /// Whether fsync posts the buffered content, keeping the handle open.
+    pub fsync_posts: bool,
+    pub dry_run: bool,
+    pub post_on_exit: bool,
+    pub allow_duplicate: bool,
+    pub template: bool,
+    pub trim: bool,
+    pub signature: Option<String>,
+    pub strict_utf8: bool,
+    /// Whether the metadata (e.g. EXIF) of the images is stripped before
+    /// uploading, and whether failing to strip it fails the post instead of
+    /// uploading the original.
+    pub strip_exif: bool,
+    pub strip_exif_strict: bool,
+    pub attr_ttl: Duration,
+    /// The directory which the large buffers are spilled to, and the size
+    /// at which they are spilled.
+    pub spill_dir: Option<PathBuf>,
+    pub spill_threshold: u64,
+    /// The maximum size of the buffer, except for the media.
+    pub max_buffer: u64,
+    /// The owner and the permission of the status files.
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub timeout: Duration,
+    /// The timeout of uploading a media, including the processing of it.
+    pub media_timeout: Duration,
+    /// The FIFO via which each status is confirmed before posting.
+    pub confirm_fifo: Option<PathBuf>,
+    /// The minimum interval between the posts.
+    pub min_interval: Option<Duration>,
+    pub timeline_count: usize,
+    pub timeline_ttl: Duration,
+}
+
+impl Config {
+    /// Parse the configuration from the environment variables.
+    ///
+    /// The invalid value is reported with the name of the variable.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            backend: match env::var("TWEET_FS_BACKEND").as_deref() {
+                Ok("twitter") | Err(..) => Backend::Twitter,
+                Ok("mastodon") => Backend::Mastodon,
+                Ok("mock") => Backend::Mock,
+                Ok(backend) => anyhow::bail!("unknown TWEET_FS_BACKEND: {}", backend),
+            },
+            source: match env::var("TWEET_FS_SOURCE") {
+                Ok(source) => {
+                    anyhow::ensure!(!source.is_empty(), "invalid TWEET_FS_SOURCE: empty");
+                    Some(source)
+                }
+                Err(..) => None,
+            },
+            allow_other: env_flag("TWEET_FS_ALLOW_OTHER"),
+            allow_root: env_flag("TWEET_FS_ALLOW_ROOT"),
+            thread_mode: env_flag("TWEET_FS_THREAD"),
+            thread_numbering: env_flag("TWEET_FS_THREAD_NUMBERING"),
+            longform: env_flag("TWEET_FS_LONGFORM"),
+            fsync_posts: env_flag("TWEET_FS_FSYNC_POSTS"),
+            lines_mode: match env::var("TWEET_FS_POST_MODE").as_deref() {
+                Ok("lines") => true,
+                Ok("status") | Err(..) => false,
+                Ok(mode) => anyhow::bail!("invalid TWEET_FS_POST_MODE: {}", mode),
+            },
+            count_mode: match env::var("TWEET_FS_COUNT_MODE").as_deref() {
+                Ok("bytes") => CountMode::Bytes,
+                Ok("chars") => CountMode::Chars,
+                Ok("weighted") | Err(..) => CountMode::Weighted,
+                Ok("graphemes") => CountMode::Graphemes,
+                Ok(mode) => anyhow::bail!("invalid TWEET_FS_COUNT_MODE: {}", mode),
+            },
+            dry_run: env_flag("TWEET_FS_DRY_RUN"),
+            post_on_exit: env_flag("TWEET_FS_POST_ON_EXIT"),
+            allow_duplicate: env_flag("TWEET_FS_ALLOW_DUPLICATE"),
+            template: env_flag("TWEET_FS_TEMPLATE"),
+            trim: env_flag("TWEET_FS_TRIM"),
+            strict_utf8: env_flag("TWEET_FS_STRICT_UTF8"),
+            strip_exif: env_flag("TWEET_FS_STRIP_EXIF"),
+            strip_exif_strict: env_flag("TWEET_FS_STRIP_EXIF_STRICT"),
+            signature: env::var("TWEET_FS_SIGNATURE")
+                .ok()
+                .filter(|signature| !signature.is_empty()),
+            attr_ttl: Duration::from_secs(env_parse("TWEET_FS_ATTR_TTL", 1)?),
+            spill_dir: env::var_os("TWEET_FS_SPILL_DIR").map(PathBuf::from),
+            spill_threshold: env_parse("TWEET_FS_SPILL_THRESHOLD", 64 * 1024)?,
+            max_buffer: env_parse("TWEET_FS_MAX_BUFFER", DEFAULT_MAX_BUFFER)?,
+            uid: env_parse("TWEET_FS_UID", unsafe { libc::getuid() })?,
+            gid: env_parse("TWEET_FS_GID", unsafe { libc::getgid() })?,
+            mode: match env::var("TWEET_FS_MODE") {
+                Ok(mode) => u32::from_str_radix(&mode, 8)
+                    .ok()
+                    .filter(|&mode| mode <= 0o777)
+                    .ok_or_else(|| anyhow::anyhow!("invalid TWEET_FS_MODE: {}", mode))?,
+                Err(..) => 0o600,
+            },
+            retries: env_parse("TWEET_FS_RETRIES", 3)?,
+            retry_delay: Duration::from_millis(env_parse("TWEET_FS_RETRY_DELAY_MS", 500)?),
+            timeout: Duration::from_secs(env_parse("TWEET_FS_TIMEOUT_SECS", 30)?),
+            media_timeout: Duration::from_secs(env_parse("TWEET_FS_MEDIA_TIMEOUT_SECS", 300)?),
+            confirm_fifo: match env::var_os("TWEET_FS_CONFIRM_FIFO") {
+                Some(path) => {
+                    let path = PathBuf::from(path);
+                    let is_fifo = fs::metadata(&path).is_ok_and(|m| m.file_type().is_fifo());
+                    anyhow::ensure!(
+                        is_fifo,
+                        "invalid TWEET_FS_CONFIRM_FIFO: {} is not a FIFO",
+                        path.display()
+                    );
+                    Some(path)
+                }
+                None => None,
+            },
+            min_interval: match env_parse("TWEET_FS_MIN_INTERVAL_SECS", 0)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            // The timeline inodes are allocated below the DM inodes.
+            timeline_count: match env_parse("TWEET_FS_TIMELINE_COUNT", 20)? {
+                count @ 1..=200 => count,
+                count => anyhow::bail!(
+                    "invalid TWEET_FS_TIMELINE_COUNT: {} (expected 1 to 200)",
+                    count
+                ),
+            },
+            timeline_ttl: Duration::from_secs(env_parse("TWEET_FS_TIMELINE_TTL", 60)?),
+        })
+    }
+}
+
+/// Return whether the specified environment variable is set to a truthy value.
+fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Parse the specified environment variable, or return `default` if it is not set.
+fn env_parse<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|err| anyhow::anyhow!("invalid {}: {}", name, err)),
+        Err(..) => Ok(default),
+    }
+}
+
+/// An additional account, exposed as a file with its name.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub credentials: Arc<Credentials>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    credentials: CredentialsSection,
+    #[serde(default)]
+    accounts: Vec<AccountSection>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountSection {
+    name: String,
+    #[serde(flatten)]
+    credentials: CredentialsSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CredentialsSection {
+    consumer_key: Option<String>,
+    consumer_secret: Option<String>,
+    access_token: Option<String>,
+    access_token_secret: Option<String>,
+    bearer_token: Option<String>,
+}
+
+/// Return the path of the configuration file.
+///
+/// The path can be overridden by `TWEET_FS_CONFIG`, and defaults to
+/// `$XDG_CONFIG_HOME/tweet-fs/config.toml` (or `~/.config/tweet-fs/config.toml`).
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("TWEET_FS_CONFIG") {
+        return Some(path.into());
+    }
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("tweet-fs").join("config.toml"))
+}
+
+fn load_config_file() -> anyhow::Result<ConfigFile> {
+    let path = match config_path() {
+        Some(path) => path,
+        None => return Ok(ConfigFile::default()),
+    };
+    match fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content)
+            .map_err(|err| anyhow::anyhow!("invalid config file {}: {}", path.display(), err)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(ConfigFile::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Load the consumer key and secret, falling back to the environment
+/// variables for the missing keys.
+///
+/// Unlike `load_credentials`, the access token is not required.
+pub fn load_consumer() -> anyhow::Result<(String, String)> {
+    let section = load_config_file()?.credentials;
+
+    let mut missing = vec![];
+    let mut get = |value, key, var| resolve(value, key, var, &mut missing);
+    let consumer = (
+        get(section.consumer_key, "consumer_key", "CONSUMER_KEY"),
+        get(
+            section.consumer_secret,
+            "consumer_secret",
+            "CONSUMER_SECRET",
+        ),
+    );
+    anyhow::ensure!(
+        missing.is_empty(),
+        "missing credentials: {}",
+        missing.join(", ")
+    );
+
+    Ok(consumer)
+}
+
+/// Load the credentials from the configuration file, falling back to
+/// the environment variables for the missing keys.
+///
+/// The keys may be missing if the bearer token is given instead, in which
+/// case the credentials cannot post the statuses.
+pub fn load_credentials() -> anyhow::Result<Credentials> {
+    let (credentials, missing) = resolve_credentials()?;
+    anyhow::ensure!(
+        missing.is_empty() || credentials.has_bearer(),
+        "missing credentials: {}",
+        missing.join(", ")
+    );
+
+    Ok(credentials)
+}
+
+/// Load the credentials as `load_credentials`, leaving the missing keys empty
+/// for the backends which do not use them.
+///
+/// The other errors, such as a malformed config file, are still returned.
+pub fn load_optional_credentials() -> anyhow::Result<Credentials> {
+    resolve_credentials().map(|(credentials, _)| credentials)
+}
+
+/// Resolve the credentials, along with the missing keys.
+fn resolve_credentials() -> anyhow::Result<(Credentials, Vec<String>)> {
+    let section = load_config_file()?.credentials;
+    let bearer_token = section
+        .bearer_token
+        .or_else(|| env::var("BEARER_TOKEN").ok())
+        .filter(|token| !token.is_empty());
+
+    let mut missing = vec![];
+    let mut get = |value, key, var| resolve(value, key, var, &mut missing);
+    let credentials = Credentials::new(
+        get(section.consumer_key, "consumer_key", "CONSUMER_KEY"),
+        get(
+            section.consumer_secret,
+            "consumer_secret",
+            "CONSUMER_SECRET",
+        ),
+        get(section.access_token, "access_token", "ACCESS_TOKEN"),
+        get(
+            section.access_token_secret,
+            "access_token_secret",
+            "ACCESS_TOKEN_SECRET",
+        ),
+        bearer_token,
+    );
+
+    Ok((credentials, missing))
+}
+
+/// Load the additional accounts from the `[[accounts]]` array in the config file.
+///
+/// The consumer key/secret of each account defaults to those of `default`.
+pub fn load_accounts(default: &Credentials) -> anyhow::Result<Vec<Account>> {
+    let mut accounts: Vec<Account> = vec![];
+    for section in load_config_file()?.accounts {
+        let name = section.name;
+        anyhow::ensure!(
+            !name.is_empty() && !name.contains('/') && name != "." && name != "..",
+            "invalid account name: {:?}",
+            name
+        );
+        anyhow::ensure!(
+            accounts.iter().all(|account| account.name != name),
+            "duplicated account name: {:?}",
+            name
+        );
+
+        let missing = |key| anyhow::anyhow!("missing {} for account {:?}", key, name);
+        let credentials = Credentials::new(
+            section
+                .credentials
+                .consumer_key
+                .unwrap_or_else(|| default.consumer_key.clone()),
+            section
+                .credentials
+                .consumer_secret
+                .unwrap_or_else(|| default.consumer_secret.clone()),
+            section
+                .credentials
+                .access_token
+                .ok_or_else(|| missing("access_token"))?,
+            section
+                .credentials
+                .access_token_secret
+                .ok_or_else(|| missing("access_token_secret"))?,
+            None,
+        );
+
+        accounts.push(Account {
+            name,
+            credentials: Arc::new(credentials),
+        });
+    }
+    Ok(accounts)
+}
+
+fn resolve(value: Option<String>, key: &str, var: &str, missing: &mut Vec<String>) -> String {
+    value.or_else(|| env::var(var).ok()).unwrap_or_else(|| {
+        missing.push(format!("{} ({})", key, var));
+        String::new()
+    })
+}
+
+/// Write the access token into the `[credentials]` section of the
+/// configuration file, keeping the rest of its content.
+pub fn save_access_token(access_token: &str, access_token_secret: &str) -> anyhow::Result<PathBuf> {
+    let path = config_path().ok_or_else(|| anyhow::anyhow!("cannot determine the config path"))?;
+
+    let mut config = match fs::read_to_string(&path) {
+        Ok(content) => content.parse::<toml::Value>()?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            toml::Value::Table(Default::default())
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let credentials = config
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("invalid config file"))?
+        .entry("credentials")
+        .or_insert_with(|| toml::Value::Table(Default::default()))
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("invalid [credentials] section"))?;
+    credentials.insert("access_token".into(), access_token.into());
+    credentials.insert("access_token_secret".into(), access_token_secret.into());
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, toml::to_string(&config)?)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+    Ok(path)
+}
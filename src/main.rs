@@ -1,160 +1,3883 @@
 #![allow(clippy::unnecessary_mut_passed)]
 #![deny(clippy::unimplemented)]
 
+mod buffer;
+mod config;
+#[cfg(feature = "poll")]
+mod poll;
+mod sink;
+#[cfg(test)]
+mod tests;
+
+use crate::{
+    buffer::{Buffer, Ranges},
+    config::{Account, Backend, Config, Credentials},
+    sink::{MastodonSink, MockSink, PostOptions, Status, TweetSink, TwitterSink},
+};
+use chrono::{DateTime, Utc};
 use polyfuse::{
     io::{Reader, Writer},
-    reply::{ReplyAttr, ReplyOpen, ReplyWrite},
-    Context, FileAttr, Filesystem, Operation,
+    reply::{ReplyAttr, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr},
+    Context, DirEntry, FileAttr, Filesystem, Operation, StatFs,
 };
+use regex::Regex;
 use slab::Slab;
-use std::{env, io, path::PathBuf, time::Duration};
+use std::{
+    cmp,
+    collections::HashMap,
+    env,
+    ffi::OsStr,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, RwLock, Weak,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::Mutex;
+use unicode_segmentation::UnicodeSegmentation;
 
-const TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
 const ROOT_INO: u64 = 1;
+const STATUS_INO: u64 = 2;
+const MEDIA_INO: u64 = 3;
+const UNDO_INO: u64 = 4;
+const WHOAMI_INO: u64 = 5;
+#[cfg(feature = "poll")]
+const POLL_INO: u64 = 6;
+const STATS_INO: u64 = 7;
+const TIMELINE_INO: u64 = 8;
+#[cfg(feature = "reactions")]
+const LIKE_INO: u64 = 9;
+#[cfg(feature = "reactions")]
+const UNLIKE_INO: u64 = 10;
+#[cfg(feature = "reactions")]
+const RETWEET_INO: u64 = 11;
+const DM_INO: u64 = 12;
+const SEARCH_INO: u64 = 13;
+const RATELIMIT_INO: u64 = 14;
+const MEDIA2_INO: u64 = 15;
+const MEDIA3_INO: u64 = 16;
+const MEDIA4_INO: u64 = 17;
+const LAST_ERROR_INO: u64 = 18;
+const DRAFT_INO: u64 = 19;
+const CONFIG_INO: u64 = 20;
+const MENTIONS_INO: u64 = 21;
+const QUOTE_LAST_INO: u64 = 22;
+
+/// The media files, in the order the media are attached to the tweet.
+const MEDIA_INOS: [u64; MAX_MEDIA] = [MEDIA_INO, MEDIA2_INO, MEDIA3_INO, MEDIA4_INO];
+
+/// The maximum number of the media attached to a tweet.
+const MAX_MEDIA: usize = 4;
+
+/// The inode number of the first account file.
+const ACCOUNT_INO_BASE: u64 = 0x1000;
+
+/// The inode number of the timeline files is this plus the position in the timeline.
+const TIMELINE_INO_BASE: u64 = 0x2000;
+
+/// The inode number of the first recipient file in the DM directory.
+const DM_INO_BASE: u64 = 0x3000;
+
+/// The maximum number of the recipients looked up in the DM directory, so that
+/// their inode numbers stay below `SEARCH_INO_BASE`.
+const MAX_DM_RECIPIENTS: usize = 0x1000;
+
+/// The inode number of the first query directory in the search directory.
+///
+/// The lower 8 bits of the inode number of a query directory are zero, and
+/// those of its result files are the position in the results, from 1.
+const SEARCH_INO_BASE: u64 = 0x10_0000;
+
+/// The maximum number of the queries looked up in the search directory.
+const MAX_SEARCH_QUERIES: usize = 0x1000;
+
+/// The maximum number of the search results.
+const MAX_SEARCH_COUNT: usize = 100;
+
+/// The interval between the tweets posted in the lines mode.
+const LINE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum length of a long-form status.
+const LONGFORM_MAX_LEN: usize = 25000;
+
+/// The maximum length of a direct message.
+const MAX_DM_LEN: usize = 10000;
+
+/// The interval of polling the confirmation FIFO.
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The fixed entries in the root directory.
+const ENTRIES: &[(&str, u64)] = &[
+    ("status", STATUS_INO), //
+    ("draft", DRAFT_INO),
+    ("media", MEDIA_INO),
+    ("media2", MEDIA2_INO),
+    ("media3", MEDIA3_INO),
+    ("media4", MEDIA4_INO),
+    ("undo", UNDO_INO),
+    ("whoami", WHOAMI_INO),
+    ("stats", STATS_INO),
+    ("ratelimit", RATELIMIT_INO),
+    ("last_error", LAST_ERROR_INO),
+    ("config", CONFIG_INO),
+    ("timeline", TIMELINE_INO),
+    ("mentions", MENTIONS_INO),
+    ("quote-last", QUOTE_LAST_INO),
+    ("dm", DM_INO),
+    ("search", SEARCH_INO),
+    #[cfg(feature = "reactions")]
+    ("like", LIKE_INO),
+    #[cfg(feature = "reactions")]
+    ("unlike", UNLIKE_INO),
+    #[cfg(feature = "reactions")]
+    ("retweet", RETWEET_INO),
+    #[cfg(feature = "poll")]
+    ("poll", POLL_INO),
+];
+
+/// The version of egg-mode, which must be kept in sync with Cargo.toml.
+const EGG_MODE_VERSION: &str = "0.13";
+
+/// The number of blocks reported by statfs.
+const STATFS_BLOCKS: u64 = 1024;
+
+/// The maximum duration to wait for the screen name naming the mount, so that
+/// the mount is not delayed while offline.
+const MOUNT_NAME_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The maximum duration to wait for the rate limit to be reset.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(15 * 60);
+
+/// The weighted length of a URL, which is always shortened by t.co.
+const URL_LEN: usize = 23;
+
+/// The configuration file of FUSE, which permits `allow_other` to the non-root users.
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// The maximum length of the alt text of a media.
+const MAX_ALT_TEXT_LEN: usize = 1000;
+
+/// The maximum size of the media accepted by Twitter.
+const MAX_IMAGE_SIZE: usize = 5 * 1024 * 1024;
+const MAX_GIF_SIZE: usize = 15 * 1024 * 1024;
+const MAX_VIDEO_SIZE: usize = 512 * 1024 * 1024;
+
+/// The major brands of the MP4 videos, in the `ftyp` box.
+///
+/// The other brands sharing the container (e.g. HEIC, AVIF and QuickTime)
+/// are not accepted by Twitter.
+const MP4_BRANDS: &[&[u8; 4]] = &[b"isom", b"iso2", b"mp41", b"mp42", b"avc1", b"M4V "];
+
+/// The ISO 639-1 codes of the languages, in the sorted order.
+#[rustfmt::skip]
+const LANGUAGES: &[&str] = &[
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az",
+    "ba", "be", "bg", "bh", "bi", "bm", "bn", "bo", "br", "bs", "ca", "ce",
+    "ch", "co", "cr", "cs", "cu", "cv", "cy", "da", "de", "dv", "dz", "ee",
+    "el", "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr",
+    "fy", "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr",
+    "ht", "hu", "hy", "hz", "ia", "id", "ie", "ig", "ii", "ik", "io", "is",
+    "it", "iu", "ja", "jv", "ka", "kg", "ki", "kj", "kk", "kl", "km", "kn",
+    "ko", "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln",
+    "lo", "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms",
+    "mt", "my", "na", "nb", "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv",
+    "ny", "oc", "oj", "om", "or", "os", "pa", "pi", "pl", "ps", "pt", "qu",
+    "rm", "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk",
+    "sl", "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta",
+    "te", "tg", "th", "ti", "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw",
+    "ty", "ug", "uk", "ur", "uz", "ve", "vi", "vo", "wa", "wo", "xh", "yi",
+    "yo", "za", "zh", "zu",
+];
 
 fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    dotenv::dotenv()?;
-    tokio_compat::run_std(async {
-        if let Err(err) = run().await {
+    let command = env::args().nth(1);
+    if let Some("--version") = command.as_deref() {
+        println!(
+            "{} {} (egg-mode {})",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            EGG_MODE_VERSION
+        );
+        return Ok(());
+    }
+
+    // The process is forked before starting the runtime, since forking
+    // a multi-threaded process is not safe.
+    let daemon = !matches!(command.as_deref(), Some("auth") | Some("check"))
+        && env::args().any(|arg| arg == "--daemon");
+    let ready = if daemon { Some(daemonize()?) } else { None };
+
+    let mut filter = tracing_subscriber::EnvFilter::from_default_env();
+    if let Some(level) = log_level()? {
+        filter = filter.add_directive(format!("tweet_fs={}", level).parse()?);
+    }
+    match env::var("TWEET_FS_LOG_FORMAT").as_deref() {
+        Ok("json") => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(filter)
+            .init(),
+        _ => tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_ansi(!daemon)
+            .init(),
+    }
+    load_env_file()?;
+    tokio_compat::run_std(async move {
+        let res = match command.as_deref() {
+            Some("auth") => auth().await,
+            Some("check") => check().await,
+            _ => run(ready).await,
+        };
+        if let Err(err) = res {
             tracing::error!("failed: {}", err);
+            std::process::exit(1);
         }
     });
     Ok(())
 }
 
-async fn run() -> anyhow::Result<()> {
-    let mountpoint = env::args_os()
-        .nth(1)
-        .map(PathBuf::from)
-        .ok_or_else(|| anyhow::anyhow!("missing mountpoint"))?;
+/// Determine the log level from `-v` (repeatable) or `TWEET_FS_LOG_LEVEL`,
+/// overriding `RUST_LOG` for this crate.
+///
+/// The flags take precedence over the variable, and step the level as
+/// info, debug and trace.
+fn log_level() -> anyhow::Result<Option<tracing::Level>> {
+    let verbosity: usize = env::args()
+        .skip(1)
+        .map(|arg| match arg.as_str() {
+            "--verbose" => 1,
+            arg if is_verbose_flag(arg) => arg.len() - 1,
+            _ => 0,
+        })
+        .sum();
+    match verbosity {
+        0 => (),
+        1 => return Ok(Some(tracing::Level::INFO)),
+        2 => return Ok(Some(tracing::Level::DEBUG)),
+        _ => return Ok(Some(tracing::Level::TRACE)),
+    }
+
+    match env::var("TWEET_FS_LOG_LEVEL") {
+        Ok(level) => level
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow::anyhow!("invalid TWEET_FS_LOG_LEVEL: {:?}", level)),
+        Err(..) => Ok(None),
+    }
+}
+
+/// Check whether the argument is `-v`, `-vv` and so on.
+fn is_verbose_flag(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with('-') && arg[1..].bytes().all(|b| b == b'v')
+}
+
+/// Load the environment variables from `.env`, or the file specified by
+/// `TWEET_FS_ENV_FILE`.
+///
+/// The missing file is not an error, since the variables may be set directly.
+fn load_env_file() -> anyhow::Result<()> {
+    let path = env::var_os("TWEET_FS_ENV_FILE").map(PathBuf::from);
+    let res = match path {
+        Some(ref path) => dotenv::from_path(path),
+        None => dotenv::dotenv().map(drop),
+    };
+    match res {
+        Err(ref err) if err.not_found() => {
+            if let Some(path) = path {
+                tracing::warn!("{} is not found", path.display());
+            }
+            Ok(())
+        }
+        res => Ok(res?),
+    }
+}
+
+/// Detach the process from the terminal with the double fork.
+///
+/// The original process exits once the daemon notifies that the filesystem
+/// is mounted, by writing to the returned pipe. The output of the daemon is
+/// redirected to `TWEET_FS_LOG_FILE`, or discarded if it is not set.
+fn daemonize() -> anyhow::Result<std::fs::File> {
+    use std::{io::Read, os::unix::io::FromRawFd};
+
+    let log = match env::var_os("TWEET_FS_LOG_FILE") {
+        Some(path) => std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?,
+        None => std::fs::OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = std::fs::File::open("/dev/null")?;
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let (mut reader, writer) = unsafe {
+        (
+            std::fs::File::from_raw_fd(fds[0]),
+            std::fs::File::from_raw_fd(fds[1]),
+        )
+    };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error().into()),
+        0 => (),
+        pid => {
+            // Wait for the daemon to be ready. The pipe is closed without
+            // writing anything if the daemon fails.
+            drop(writer);
+            unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+            let mut buf = [0; 1];
+            match reader.read(&mut buf)? {
+                0 => {
+                    eprintln!("failed to start the daemon");
+                    std::process::exit(1);
+                }
+                _ => std::process::exit(0),
+            }
+        }
+    }
+    drop(reader);
+
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error().into()),
+        0 => (),
+        _ => std::process::exit(0),
+    }
+
+    // The working directory is kept as is, since the mountpoint and the
+    // env file may be given as the relative paths.
+    unsafe {
+        use std::os::unix::io::AsRawFd;
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+
+    Ok(writer)
+}
+
+async fn run(ready: Option<std::fs::File>) -> anyhow::Result<()> {
+    let mut create = false;
+    let mut force_unmount = false;
+    let config = Config::from_env()?;
+    let mut allow_other = config.allow_other;
+    let mut allow_root = config.allow_root;
+    let mut name = None;
+    let mut mountpoint = None;
+    let mut args = env::args_os().skip(1).collect::<Vec<_>>().into_iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--create") => create = true,
+            Some("--force-unmount") => force_unmount = true,
+            Some("--name") => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("missing --name value"))?;
+                name = Some(
+                    value
+                        .into_string()
+                        .map_err(|_| anyhow::anyhow!("invalid --name"))?,
+                );
+            }
+            Some(arg) if arg.starts_with("--name=") => {
+                name = Some(arg["--name=".len()..].to_owned())
+            }
+            Some("--daemon") | Some("--foreground") | Some("--verbose") => (),
+            Some(arg) if is_verbose_flag(arg) => (),
+            Some("--allow-other") => allow_other = true,
+            Some("--allow-root") => allow_root = true,
+            _ => mountpoint = Some(PathBuf::from(arg)),
+        }
+    }
+    let mountpoint = mountpoint.ok_or_else(|| anyhow::anyhow!("missing mountpoint"))?;
+    check_mountpoint(&mountpoint, create, force_unmount)?;
+
+    let mut mountopts: Vec<&OsStr> = vec![];
+    match (allow_other, allow_root) {
+        (true, true) => anyhow::bail!("--allow-other and --allow-root are mutually exclusive"),
+        (true, false) => mountopts.extend(&[OsStr::new("-o"), OsStr::new("allow_other")]),
+        (false, true) => mountopts.extend(&[OsStr::new("-o"), OsStr::new("allow_root")]),
+        (false, false) => (),
+    }
+    if (allow_other || allow_root) && unsafe { libc::getuid() } != 0 {
+        anyhow::ensure!(
+            user_allow_other()?,
+            "--allow-other and --allow-root require user_allow_other in {}",
+            FUSE_CONF
+        );
+    }
+
+    let fs = TweetFS::new(config)?;
+
+    // The mount is named after the account, so that it is identifiable in /proc/mounts.
+    // The name is not looked up in the dry run, which never accesses the network.
+    let name = match name {
+        Some(name) => Some(name),
+        None if fs.config.dry_run => None,
+        None => {
+            let timeout = cmp::min(fs.config.timeout, MOUNT_NAME_TIMEOUT);
+            match tokio::time::timeout(timeout, fs.whoami()).await {
+                Ok(Ok(screen_name)) => Some(screen_name),
+                Ok(Err(..)) => None,
+                Err(..) => {
+                    tracing::warn!("the screen name is not looked up in time, mounting without it");
+                    None
+                }
+            }
+        }
+    };
+    let fsname = match name {
+        Some(name) => {
+            anyhow::ensure!(!name.contains(','), "invalid --name: {:?}", name);
+            format!("fsname=tweet-fs-{},subtype=tweetfs", name)
+        }
+        None => "fsname=tweet-fs,subtype=tweetfs".to_owned(),
+    };
+    mountopts.extend(&[OsStr::new("-o"), OsStr::new(&fsname)]);
+
+    let mut server = polyfuse_tokio::Server::mount(mountpoint, &mountopts)
+        .await
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::PermissionDenied if allow_other || allow_root => anyhow::anyhow!(
+                "failed to mount: {} (check that user_allow_other is set in {})",
+                err,
+                FUSE_CONF
+            ),
+            _ => err.into(),
+        })?;
+
+    if let Some(mut ready) = ready {
+        io::Write::write_all(&mut ready, b"1")?;
+    }
+    #[cfg(feature = "systemd")]
+    notify_systemd(sd_notify::NotifyState::Ready);
+
+    if let Some(sig) = server.run_until(fs.clone(), shutdown_signal()?).await? {
+        tracing::info!("received {}, unmounting", sig);
+    }
+    #[cfg(feature = "systemd")]
+    notify_systemd(sd_notify::NotifyState::Stopping);
+    // The filesystem is unmounted when the server is dropped.
+    drop(server);
+
+    fs.shutdown().await;
+
+    Ok(())
+}
+
+/// Notify systemd of the state, which does nothing unless run by systemd.
+#[cfg(feature = "systemd")]
+fn notify_systemd(state: sd_notify::NotifyState<'_>) {
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        tracing::warn!("failed to notify systemd: {}", err);
+    }
+}
+
+/// Describe the type of the file found at the mountpoint.
+fn file_type_name(file_type: std::fs::FileType) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+    if file_type.is_file() {
+        "a regular file"
+    } else if file_type.is_symlink() {
+        "a symbolic link"
+    } else if file_type.is_fifo() {
+        "a FIFO"
+    } else if file_type.is_socket() {
+        "a socket"
+    } else if file_type.is_block_device() {
+        "a block device"
+    } else if file_type.is_char_device() {
+        "a character device"
+    } else {
+        "an unknown file type"
+    }
+}
+
+/// Check that the mountpoint is a directory, creating it if `create` is set.
+///
+/// The stale mount left by the crashed process is unmounted if `force_unmount` is set.
+fn check_mountpoint(mountpoint: &Path, create: bool, force_unmount: bool) -> anyhow::Result<()> {
+    match std::fs::metadata(mountpoint) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(metadata) => anyhow::bail!(
+            "invalid mountpoint {}: expected a directory, found {}",
+            mountpoint.display(),
+            file_type_name(metadata.file_type())
+        ),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            // The symbolic link itself is found if it is dangling.
+            if let Ok(metadata) = std::fs::symlink_metadata(mountpoint) {
+                anyhow::bail!(
+                    "invalid mountpoint {}: expected a directory, found {} to a missing path",
+                    mountpoint.display(),
+                    file_type_name(metadata.file_type())
+                );
+            }
+            anyhow::ensure!(
+                create,
+                "the mountpoint {} does not exist (pass --create to create it)",
+                mountpoint.display()
+            );
+            std::fs::create_dir_all(mountpoint)?;
+            tracing::info!("created the mountpoint {}", mountpoint.display());
+            Ok(())
+        }
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOTCONN) => {
+            anyhow::ensure!(
+                force_unmount,
+                "the mountpoint {} is a stale mount left by the previous process (pass --force-unmount to unmount it)",
+                mountpoint.display()
+            );
+            unmount_stale(mountpoint)?;
+            tracing::info!("unmounted the stale mount on {}", mountpoint.display());
+            check_mountpoint(mountpoint, create, false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Unmount the stale FUSE mount, with `fusermount` or `umount` as root.
+fn unmount_stale(mountpoint: &Path) -> anyhow::Result<()> {
+    use std::process::Command;
+
+    let status = Command::new("fusermount")
+        .arg("-u")
+        .arg("-z")
+        .arg(mountpoint)
+        .status();
+    match status {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => tracing::warn!("fusermount exited with {}", status),
+        Err(err) => tracing::warn!("failed to run fusermount: {}", err),
+    }
+
+    let status = Command::new("umount").arg("-l").arg(mountpoint).status()?;
     anyhow::ensure!(
-        mountpoint.is_file(),
-        "the mountpoint must be a regular file"
+        status.success(),
+        "failed to unmount the stale mount on {}",
+        mountpoint.display()
     );
+    Ok(())
+}
+
+/// Check whether `user_allow_other` is enabled in the FUSE configuration.
+fn user_allow_other() -> anyhow::Result<bool> {
+    match std::fs::read_to_string(FUSE_CONF) {
+        Ok(conf) => Ok(conf.lines().any(|line| line.trim() == "user_allow_other")),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Wait for SIGINT or SIGTERM.
+fn shutdown_signal() -> io::Result<impl Future<Output = &'static str> + Unpin> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    Ok(Box::pin(async move {
+        use futures::future::{select, Either};
+        match select(Box::pin(sigint.recv()), Box::pin(sigterm.recv())).await {
+            Either::Left(..) => "SIGINT",
+            Either::Right(..) => "SIGTERM",
+        }
+    }))
+}
+
+/// Verify the credentials without mounting, and report the account and the rate limit.
+async fn check() -> anyhow::Result<()> {
+    let fs = TweetFS::new(Config::from_env()?)?;
+    let res = fs
+        .with_timeout(fs.sink.verify(&fs.default_credentials()))
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to verify the credentials: {}", err))?;
+
+    println!("authenticated as @{}", res.response);
+    if res.rate_limit_reset >= 0 {
+        let reset =
+            DateTime::from_timestamp(i64::from(res.rate_limit_reset), 0).unwrap_or_default();
+        println!(
+            "rate limit: {} of {} remaining, reset at {}",
+            res.rate_limit_remaining,
+            res.rate_limit,
+            reset.to_rfc3339()
+        );
+    } else {
+        println!("rate limit: unknown");
+    }
+    for account in &fs.accounts {
+        match fs.with_timeout(fs.sink.verify(&account.credentials)).await {
+            Ok(res) => println!(
+                "account {}: authenticated as @{}",
+                account.name, res.response
+            ),
+            Err(err) => anyhow::bail!("failed to verify the account {}: {}", account.name, err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Obtain the access token with the PIN-based authorization and save it
+/// into the config file.
+async fn auth() -> anyhow::Result<()> {
+    use futures::compat::Future01CompatExt;
+
+    let (consumer_key, consumer_secret) = config::load_consumer()?;
+    let consumer = egg_mode::KeyPair::new(consumer_key, consumer_secret);
+
+    let request_token = egg_mode::request_token(&consumer, "oob").compat().await?;
+    println!("Open the following URL and authorize the application:");
+    println!("{}", egg_mode::authorize_url(&request_token));
+    print!("PIN: ");
+    io::Write::flush(&mut io::stdout())?;
+
+    let mut pin = String::new();
+    if io::stdin().read_line(&mut pin)? == 0 {
+        anyhow::bail!("aborted");
+    }
+    let pin = pin.trim();
+    anyhow::ensure!(!pin.is_empty(), "empty PIN");
+
+    let (token, _user_id, screen_name) = egg_mode::access_token(consumer, &request_token, pin)
+        .compat()
+        .await?;
+    let access = match token {
+        egg_mode::Token::Access { access, .. } => access,
+        egg_mode::Token::Bearer(..) => anyhow::bail!("unexpected bearer token"),
+    };
 
-    polyfuse_tokio::mount(TweetFS::new()?, mountpoint, &[]).await?;
+    let path = config::save_access_token(&access.key, &access.secret)?;
+    println!(
+        "Authorized as @{}; saved to {}",
+        screen_name,
+        path.display()
+    );
 
     Ok(())
 }
 
+/// An opened file.
+struct File {
+    ino: u64,
+    generation: u32,
+    content: Buffer,
+    /// Whether the content has been posted by fsync and nothing is written since.
+    synced: bool,
+    /// The total length of the contents posted by fsync, which the offsets of
+    /// the following writes and the truncated sizes are relative to.
+    synced_len: u64,
+    /// The ranges of the content actually written, to detect the gaps.
+    written: Ranges,
+    /// Whether the content has been written or truncated since opened.
+    modified: bool,
+}
+
+/// The opened files.
+///
+/// The file handle consists of the slab key in the lower 32 bits and the
+/// generation in the upper 32 bits, so that a stale handle is not confused
+/// with another file opened after the key is reused.
+#[derive(Default)]
+struct Files {
+    slab: Slab<File>,
+    generation: u32,
+}
+
+impl Files {
+    fn insert(&mut self, ino: u64) -> u64 {
+        self.generation = self.generation.wrapping_add(1);
+        let key = self.slab.insert(File {
+            ino,
+            generation: self.generation,
+            content: Buffer::default(),
+            synced: false,
+            synced_len: 0,
+            written: Ranges::default(),
+            modified: false,
+        });
+        file_handle(key, self.generation)
+    }
+
+    fn get(&self, fh: u64) -> Option<&File> {
+        self.slab
+            .get((fh & 0xffff_ffff) as usize)
+            .filter(|file| file.generation == (fh >> 32) as u32)
+    }
+
+    fn get_mut(&mut self, fh: u64) -> Option<&mut File> {
+        self.slab
+            .get_mut((fh & 0xffff_ffff) as usize)
+            .filter(|file| file.generation == (fh >> 32) as u32)
+    }
+
+    fn remove(&mut self, fh: u64) -> Option<File> {
+        self.get(fh)?;
+        Some(self.slab.remove((fh & 0xffff_ffff) as usize))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u64, &File)> {
+        self.slab
+            .iter()
+            .map(|(key, file)| (file_handle(key, file.generation), file))
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (u64, &mut File)> {
+        self.slab
+            .iter_mut()
+            .map(|(key, file)| (file_handle(key, file.generation), file))
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = File> + '_ {
+        self.slab.drain()
+    }
+}
+
+fn file_handle(key: usize, generation: u32) -> u64 {
+    u64::from(generation) << 32 | key as u64
+}
+
+/// The options of the next tweet, set via the extended attributes.
+#[derive(Debug, Default)]
+struct Xattrs {
+    in_reply_to: Option<u64>,
+    /// The users excluded from the mentions of the reply.
+    exclude_reply_user_ids: Vec<u64>,
+    /// The URL of the quoted tweet.
+    quote: Option<String>,
+    lat: Option<f64>,
+    long: Option<f64>,
+    schedule: Option<DateTime<Utc>>,
+    /// The content warning of the statuses, supported only by Mastodon.
+    content_warning: Option<String>,
+    /// The language of the statuses, supported only by Mastodon.
+    lang: Option<String>,
+    /// Whether to pin the posted status to the profile, supported only by Mastodon.
+    pin: bool,
+    /// The index of the account posting the status instead of the file's one.
+    account: Option<usize>,
+}
+
+/// The options of the staged media, set via the extended attributes on
+/// the media file.
+#[derive(Debug, Default)]
+struct MediaXattrs {
+    alt: Option<String>,
+    sensitive: bool,
+}
+
 struct TweetFS {
-    files: Mutex<Slab<Vec<u8>>>,
-    consumer_secret: String,
-    consumer_key: String,
-    access_token: String,
-    access_token_secret: String,
+    /// The reference to itself, for spawning the tasks posting the
+    /// scheduled tweets.
+    this: Weak<Self>,
+    files: Mutex<Files>,
+    /// The opened directory handles, holding the inode of each directory.
+    dirs: Mutex<Slab<u64>>,
+    /// The content written to the draft file, posted by renaming it to `send`.
+    draft: Mutex<Vec<u8>>,
+    /// The media staged in each media file.
+    media: Mutex<[Vec<u8>; MAX_MEDIA]>,
+    /// The options of the staged media, for each media file.
+    media_xattrs: Mutex<[MediaXattrs; MAX_MEDIA]>,
+    /// The extended attributes are set on the inode rather than the opened
+    /// file, so they are kept until the next status written to the inode is
+    /// released.
+    xattrs: Mutex<HashMap<u64, Xattrs>>,
+    /// The inode which the last tweet is posted from, and its ID.
+    last_tweet: Mutex<Option<(u64, u64)>>,
+    /// The IDs of the tweets in the last posted thread, in order.
+    last_thread: Mutex<Vec<u64>>,
+    /// The rest of the last thread which failed to be posted.
+    unposted: Mutex<Option<String>>,
+    /// The last posted status, to reject the duplicated one.
+    last_status: Mutex<Option<String>>,
+    /// The screen name of the default account, once verified.
+    screen_name: Mutex<Option<String>>,
+    /// The duration of the next poll in minutes.
+    #[cfg(feature = "poll")]
+    poll_duration: Mutex<Option<u32>>,
+    /// The credentials of the default account, replaced when reloaded.
+    credentials: RwLock<Arc<Credentials>>,
+    /// Whether only the bearer token is given, where all writes are rejected.
+    read_only: bool,
+    accounts: Vec<Account>,
+    sink: Box<dyn TweetSink>,
+    config: Config,
+    rate_limit_reset: Mutex<Option<Instant>>,
+    /// When the last status is posted, for pacing by the minimum interval.
+    last_posted: Mutex<Option<Instant>>,
+    /// The remaining calls and the reset time (in UNIX time) of the rate limit
+    /// seen in the last response.
+    rate_limit: Mutex<Option<(i32, i32)>>,
+    /// The scheduled time of the tweets waiting to be posted, and the inode
+    /// posting them.
+    scheduled: Mutex<Slab<(DateTime<Utc>, u64)>>,
+    /// The number of the statuses posted and failed, reported by the stats file.
+    posted: AtomicU64,
+    failures: AtomicU64,
+    /// The reason of the last failed post, cleared once a status is posted.
+    last_error: Mutex<Option<String>>,
+    /// The status liked or unliked last, and its favorite count.
+    #[cfg(feature = "reactions")]
+    last_liked: Mutex<Option<(u64, u64)>>,
+    /// The ID of the last retweet.
+    #[cfg(feature = "reactions")]
+    last_retweet: Mutex<Option<u64>>,
+    /// Held while a status is confirmed, so that the FIFO is used by one at a time.
+    confirming: Mutex<()>,
+    /// The ID of the timeline item or the mention read last, quoted by quote-last.
+    last_read: std::sync::Mutex<Option<u64>>,
+    /// The recipients of the direct messages looked up in the DM directory,
+    /// indexed by the inode number relative to `DM_INO_BASE`.
+    dm_recipients: std::sync::Mutex<Vec<String>>,
+    /// The resolved user IDs of the screen names.
+    user_ids: Mutex<HashMap<String, u64>>,
+    /// The queries looked up in the search directory, indexed by the inode
+    /// number of the query directory.
+    search_queries: std::sync::Mutex<Vec<String>>,
+    /// The search results fetched last for each query, and when they are fetched.
+    searches: Mutex<HashMap<String, (Instant, Vec<Status>)>>,
+    /// The home timeline fetched last, and when it is fetched.
+    timeline: Mutex<Option<(Instant, Vec<Status>)>>,
+    /// The mentions fetched last, and when they are fetched.
+    mentions: Mutex<Option<(Instant, Vec<Status>)>>,
 }
 
 impl TweetFS {
-    fn new() -> anyhow::Result<Self> {
-        Ok(Self {
-            files: Mutex::default(),
-            consumer_key: env::var("CONSUMER_KEY")?,
-            consumer_secret: env::var("CONSUMER_SECRET")?,
-            access_token: env::var("ACCESS_TOKEN")?,
-            access_token_secret: env::var("ACCESS_TOKEN_SECRET")?,
-        })
+    fn new(config: Config) -> anyhow::Result<Arc<Self>> {
+        let sink: Box<dyn TweetSink> = match config.backend {
+            Backend::Twitter => Box::new(TwitterSink),
+            Backend::Mastodon => Box::new(MastodonSink::from_env()?),
+            Backend::Mock => Box::new(MockSink::default()),
+        };
+        Self::with_sink(config, sink)
     }
-}
 
-#[polyfuse::async_trait]
-impl Filesystem for TweetFS {
-    async fn call<'a, 'cx, T: ?Sized>(
-        &'a self,
-        cx: &'a mut Context<'cx, T>,
-        op: Operation<'cx>,
-    ) -> io::Result<()>
-    where
-        T: Reader + Writer + Unpin + Send,
-    {
-        tracing::debug!("op={:?}", op);
-        match op {
-            Operation::Getattr(..) => {
-                let mut attr = FileAttr::default();
-                attr.set_mode(libc::S_IFREG as u32 | 0o200);
-                attr.set_ino(ROOT_INO);
-                attr.set_nlink(1);
-                attr.set_uid(unsafe { libc::getuid() });
-                attr.set_gid(unsafe { libc::getgid() });
+    /// Create the filesystem posting to the given backend.
+    fn with_sink(mut config: Config, sink: Box<dyn TweetSink>) -> anyhow::Result<Arc<Self>> {
+        if config.longform && !sink.supports_longform() {
+            tracing::warn!(
+                "the long-form statuses are not supported by {}, and split into a thread instead",
+                config.backend.name()
+            );
+            config.longform = false;
+            config.thread_mode = true;
+        }
 
-                cx.reply(
-                    ReplyAttr::new(attr) //
-                        .ttl_attr(TTL),
-                )
-                .await?;
+        // The other backends do not use the credentials of Twitter.
+        let credentials = match config.backend {
+            Backend::Twitter => config::load_credentials()?,
+            _ => config::load_optional_credentials()?,
+        };
 
-                Ok(())
+        // The source label is determined by the app which the token is issued to,
+        // and neither Twitter nor Mastodon allows overriding it per status.
+        if config.source.is_some() {
+            tracing::warn!(
+                "the source label cannot be set by {}, and TWEET_FS_SOURCE is ignored",
+                config.backend.name()
+            );
+        }
+
+        let read_only = config.backend == Backend::Twitter && !credentials.can_post();
+        if read_only {
+            tracing::warn!("the access token is not given, and posting is disabled");
+        }
+
+        let accounts = config::load_accounts(&credentials)?;
+        anyhow::ensure!(
+            accounts.is_empty() || config.backend == Backend::Twitter,
+            "[[accounts]] is not supported by {}",
+            config.backend.name()
+        );
+        for account in &accounts {
+            anyhow::ensure!(
+                ENTRIES.iter().all(|&(name, _)| name != account.name),
+                "the account name {:?} is reserved",
+                account.name
+            );
+        }
+
+        let mut fs = Self {
+            this: Weak::new(),
+            files: Mutex::default(),
+            dirs: Mutex::default(),
+            draft: Mutex::default(),
+            media: Mutex::default(),
+            media_xattrs: Mutex::default(),
+            xattrs: Mutex::default(),
+            last_tweet: Mutex::default(),
+            last_thread: Mutex::default(),
+            unposted: Mutex::default(),
+            last_status: Mutex::default(),
+            screen_name: Mutex::default(),
+            #[cfg(feature = "poll")]
+            poll_duration: Mutex::default(),
+            credentials: RwLock::new(Arc::new(credentials)),
+            read_only,
+            accounts,
+            sink,
+            config,
+            rate_limit_reset: Mutex::default(),
+            last_posted: Mutex::default(),
+            rate_limit: Mutex::default(),
+            scheduled: Mutex::default(),
+            posted: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            last_error: Mutex::default(),
+            #[cfg(feature = "reactions")]
+            last_liked: Mutex::default(),
+            #[cfg(feature = "reactions")]
+            last_retweet: Mutex::default(),
+            confirming: Mutex::default(),
+            last_read: std::sync::Mutex::default(),
+            dm_recipients: std::sync::Mutex::default(),
+            search_queries: std::sync::Mutex::default(),
+            searches: Mutex::default(),
+            user_ids: Mutex::default(),
+            timeline: Mutex::default(),
+            mentions: Mutex::default(),
+        };
+
+        Ok(Arc::new_cyclic(move |this| {
+            fs.this = this.clone();
+            fs
+        }))
+    }
+
+    /// Post the status, retrying on the transient failures with exponential backoff.
+    async fn send_tweet(
+        &self,
+        credentials: &Credentials,
+        opts: &PostOptions<'_>,
+    ) -> Result<egg_mode::Response<u64>, egg_mode::error::Error> {
+        use rand::Rng;
+
+        // The lock is held until posted, so that the concurrent posts are paced.
+        let mut last_posted = match self.config.min_interval {
+            Some(interval) => {
+                let last_posted = self.last_posted.lock().await;
+                if let Some(at) = *last_posted {
+                    let elapsed = at.elapsed();
+                    if elapsed < interval {
+                        tracing::info!("wait {:?} for the minimum interval", interval - elapsed);
+                        tokio::time::delay_for(interval - elapsed).await;
+                    }
+                }
+                Some(last_posted)
             }
-            Operation::Open(op) => {
-                match op.flags() as libc::c_int & libc::O_ACCMODE {
-                    libc::O_WRONLY => (),
-                    _ => return cx.reply_err(libc::EPERM).await,
+            None => None,
+        };
+
+        let mut attempt = 0;
+        let mut rate_limited = false;
+        loop {
+            self.wait_rate_limit().await;
+
+            match self.with_timeout(self.sink.post(credentials, opts)).await {
+                Ok(tweet) => {
+                    // The backends other than Twitter report no rate limit.
+                    if tweet.rate_limit_reset >= 0 {
+                        *self.rate_limit.lock().await =
+                            Some((tweet.rate_limit_remaining, tweet.rate_limit_reset));
+                    }
+                    if let Some(ref mut last_posted) = last_posted {
+                        **last_posted = Some(Instant::now());
+                    }
+                    *self.rate_limit_reset.lock().await = if tweet.rate_limit_remaining > 0 {
+                        None
+                    } else {
+                        Some(reset_instant(tweet.rate_limit_reset))
+                    };
+                    return Ok(tweet);
+                }
+                Err(egg_mode::error::Error::RateLimit(reset)) if !rate_limited => {
+                    // wait until the rate limit is reset, rather than the backoff.
+                    tracing::warn!("rate limit reached, hold until {}", reset);
+                    *self.rate_limit_reset.lock().await = Some(reset_instant(reset));
+                    *self.rate_limit.lock().await = Some((0, reset));
+                    rate_limited = true;
                 }
+                Err(ref err) if attempt < self.config.retries && is_retryable(err) => {
+                    let delay = self.config.retry_delay * 2u32.pow(cmp::min(attempt, 10));
+                    let jitter = rand::thread_rng().gen_range(0, delay.as_millis() as u64 + 1);
+                    let delay = delay + Duration::from_millis(jitter);
+                    tracing::warn!("failed to post the tweet, retry after {:?}: {}", delay, err);
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
 
-                let mut files = self.files.lock().await;
-                let key = files.insert(vec![]);
+    /// Fail the API call with `TimedOut` if it does not complete within `timeout`.
+    ///
+    /// The timed out status is not kept, and must be written again.
+    async fn with_timeout<T>(
+        &self,
+        call: impl Future<Output = Result<T, egg_mode::error::Error>>,
+    ) -> Result<T, egg_mode::error::Error> {
+        self.with_timeout_of(self.config.timeout, call).await
+    }
 
-                cx.reply(
-                    ReplyOpen::new(key as u64) //
-                        .direct_io(true)
-                        .keep_cache(false),
-                )
-                .await?;
+    /// Fail the API call with `TimedOut` if it does not complete within the given timeout.
+    async fn with_timeout_of<T>(
+        &self,
+        timeout: Duration,
+        call: impl Future<Output = Result<T, egg_mode::error::Error>>,
+    ) -> Result<T, egg_mode::error::Error> {
+        match tokio::time::timeout(timeout, call).await {
+            Ok(res) => res,
+            Err(..) => Err(egg_mode::error::Error::IOError(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the request timed out",
+            ))),
+        }
+    }
 
-                Ok(())
+    /// Wait until the rate limit seen in the last response is reset.
+    async fn wait_rate_limit(&self) {
+        let reset = *self.rate_limit_reset.lock().await;
+        if let Some(reset) = reset {
+            let delay = reset.saturating_duration_since(Instant::now());
+            if delay > Duration::from_secs(0) {
+                tracing::info!("wait {:?} for the rate limit to be reset", delay);
+                tokio::time::delay_for(delay).await;
             }
-            Operation::Write(op) => {
-                let mut files = self.files.lock().await;
-                let content = match files.get_mut(op.fh() as usize) {
-                    Some(file) => file,
-                    None => return cx.reply_err(libc::EIO).await,
-                };
+        }
+    }
 
-                let offset = op.offset() as usize;
-                let size = op.size() as usize;
-                content.resize(offset + size, 0);
-                {
-                    use futures::io::AsyncReadExt;
-                    let mut reader = cx.reader();
-                    reader
-                        .read_exact(&mut content[offset..offset + size])
-                        .await
-                        .unwrap();
-                }
+    /// Handle the buffers still opened on exit.
+    ///
+    /// The statuses are posted if `post_on_exit` is set, and discarded otherwise.
+    async fn shutdown(&self) {
+        let scheduled = self.scheduled.lock().await.len();
+        if scheduled > 0 {
+            tracing::warn!("dropped {} scheduled tweet(s)", scheduled);
+        }
 
-                cx.reply(ReplyWrite::new(op.size())).await?;
-                Ok(())
+        let pending: Vec<File> = self
+            .files
+            .lock()
+            .await
+            .drain()
+            // The draft loaded on open is pending only if it is modified.
+            .filter(|file| !file.content.is_empty() && (file.ino != DRAFT_INO || file.modified))
+            .collect();
+        if pending.is_empty() {
+            return;
+        }
+
+        if !self.config.post_on_exit {
+            tracing::warn!("dropped {} pending buffer(s)", pending.len());
+            return;
+        }
+
+        let (mut posted, mut dropped) = (0, 0);
+        for file in pending {
+            if self.credentials(file.ino).is_none() || file.ino == DRAFT_INO {
+                dropped += 1;
+                continue;
             }
-            Operation::Release(op) => {
-                let mut files = self.files.lock().await;
+            let content = match file.content.into_vec() {
+                Ok(content) => content,
+                Err(err) => {
+                    tracing::error!("failed to read the spilled buffer: {}", err);
+                    dropped += 1;
+                    continue;
+                }
+            };
+            // The buffer is posted as on release, except that nothing is replied.
+            let content = match check_written(content, &file.written) {
+                Ok(content) => content,
+                Err(..) => {
+                    dropped += 1;
+                    continue;
+                }
+            };
+            if self.submit(file.ino, content).await.is_ok() {
+                posted += 1;
+            } else {
+                dropped += 1;
+            }
+        }
+        tracing::info!("posted {} pending buffer(s), dropped {}", posted, dropped);
+    }
 
-                let status = files.remove(op.fh() as usize);
-                let status = String::from_utf8_lossy(&status).into_owned();
+    /// Insert the handle opened on the inode.
+    ///
+    /// The buffer of the draft starts from the saved draft, so that the appended
+    /// writes follow it rather than replacing it.
+    async fn open_file(&self, ino: u64) -> u64 {
+        let mut files = self.files.lock().await;
+        let fh = files.insert(ino);
+        if ino == DRAFT_INO {
+            let draft = self.draft.lock().await.clone();
+            let file = files.get_mut(fh).expect("the handle is just inserted");
+            file.written.insert(0..draft.len() as u64);
+            file.content = Buffer::Mem(draft);
+        }
+        fh
+    }
 
-                tracing::debug!("tweet: status={:?}", status);
+    /// Take the extended attributes set on the inode.
+    async fn take_xattrs(&self, ino: u64) -> Xattrs {
+        self.xattrs.lock().await.remove(&ino).unwrap_or_default()
+    }
 
-                use futures::compat::Future01CompatExt;
-                let res = egg_mode::tweet::DraftTweet::new(&status)
-                    .send(&egg_mode::Token::Access {
-                        consumer: egg_mode::KeyPair {
-                            key: self.consumer_key.clone().into(),
-                            secret: self.consumer_secret.clone().into(),
-                        },
-                        access: egg_mode::KeyPair {
-                            key: self.access_token.clone().into(),
-                            secret: self.access_token_secret.clone().into(),
-                        },
-                    })
-                    .compat()
-                    .await;
-                tracing::debug!("tweet result: {:?}", res);
+    /// Spawn the task posting the status at the scheduled time.
+    ///
+    /// The scheduled tweets are kept only in memory, and lost on exit.
+    async fn schedule(&self, at: DateTime<Utc>, ino: u64, content: Vec<u8>, xattrs: Xattrs) {
+        let this = match self.this.upgrade() {
+            Some(this) => this,
+            None => return,
+        };
+        let key = self.scheduled.lock().await.insert((at, ino));
+        tracing::info!("scheduled the tweet at {}", at);
 
-                cx.reply(()).await?;
-                Ok(())
+        tokio::spawn(async move {
+            let delay = (at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::delay_for(delay).await;
+            this.scheduled.lock().await.remove(key);
+            if let Err(errno) = this.post_counted(ino, &content, xattrs).await {
+                tracing::error!("failed to post the scheduled tweet (errno = {})", errno);
             }
-            _ => Ok(()),
+        });
+    }
+
+    /// Compose the status from the written content, trimming the whitespace,
+    /// expanding the template and appending the signature.
+    fn compose(&self, content: &[u8]) -> String {
+        let mut status = String::from_utf8_lossy(content).into_owned();
+        if self.config.trim {
+            status = status.trim().to_owned();
+        }
+        if self.config.template {
+            status = expand_template(&status, &chrono::Local::now(), &hostname());
+        }
+        match self.config.signature {
+            Some(ref signature) => format!("{} {}", status.trim_end(), signature),
+            None => status,
+        }
+    }
+
+    /// Split the status into the chunks posted as a thread.
+    fn chunks(&self, status: &str) -> Vec<String> {
+        if self.config.lines_mode {
+            status
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(ToOwned::to_owned)
+                .collect()
+        } else if self.config.thread_mode && self.config.thread_numbering {
+            split_numbered(status, self.max_len(), self.config.count_mode)
+        } else if self.config.thread_mode {
+            split_thread(status, self.max_len(), self.config.count_mode)
+        } else {
+            vec![status.to_owned()]
+        }
+    }
+
+    /// Render the preview of the tweets to be posted from the written content.
+    ///
+    /// The chunks of a thread are shown with the separators.
+    fn preview(&self, content: &[u8]) -> String {
+        let chunks = self.chunks(&self.compose(content));
+        match &chunks[..] {
+            [status] => status.clone(),
+            chunks => chunks
+                .iter()
+                .enumerate()
+                .map(|(i, chunk)| format!("--- tweet {} ---\n{}\n", i + 1, chunk))
+                .collect(),
         }
     }
+
+    /// Post the status written to the inode.
+    async fn post(&self, ino: u64, content: &[u8], xattrs: Xattrs) -> Result<(), libc::c_int> {
+        // The status is posted as if written to the file of the overriding account,
+        // so that it is deleted from the same account by undo.
+        let ino = match xattrs.account {
+            Some(i) => ACCOUNT_INO_BASE + i as u64,
+            None => ino,
+        };
+
+        let status = self.compose(content);
+        if (self.config.signature.is_some() || self.config.template)
+            && !self.config.thread_mode
+            && !self.config.lines_mode
+            && self.config.count_mode.len(&status) > self.max_len()
+        {
+            let reason = "the status is too long to expand the template or append the signature";
+            return Err(self.fail(libc::EFBIG, reason.into()).await);
+        }
+
+        if !self.config.allow_duplicate && self.last_status.lock().await.as_ref() == Some(&status) {
+            let reason = "the status is identical to the last one";
+            return Err(self.fail(libc::EALREADY, reason.into()).await);
+        }
+
+        tracing::debug!(
+            status = status.as_str(),
+            len = status.len(),
+            ?xattrs,
+            "tweet"
+        );
+
+        let coordinates = match (xattrs.lat, xattrs.long) {
+            (Some(lat), Some(long)) => Some((lat, long)),
+            (None, None) => None,
+            _ => {
+                tracing::warn!("both user.tweet.lat and user.tweet.long are required");
+                None
+            }
+        };
+
+        if self.config.lines_mode {
+            let too_long: Vec<_> = status
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| self.config.count_mode.len(line.trim()) > self.max_len())
+                .map(|(i, _)| i + 1)
+                .collect();
+            if !too_long.is_empty() {
+                let reason = format!("the lines {:?} are too long to be posted", too_long);
+                return Err(self.fail(libc::EFBIG, reason).await);
+            }
+        }
+
+        let chunks = self.chunks(&status);
+
+        if self.config.dry_run {
+            let media = std::mem::take(&mut *self.media.lock().await);
+            let media_xattrs = std::mem::take(&mut *self.media_xattrs.lock().await);
+            for (data, media_xattrs) in media.iter().zip(&media_xattrs) {
+                if data.is_empty() {
+                    continue;
+                }
+                tracing::info!("dry-run: media={} bytes", data.len());
+                if let Some(ref alt) = media_xattrs.alt {
+                    tracing::info!("dry-run: alt={:?}", alt);
+                }
+                if media_xattrs.sensitive {
+                    tracing::info!("dry-run: sensitive");
+                }
+            }
+            if let Some(id) = xattrs.in_reply_to {
+                tracing::info!("dry-run: in_reply_to={}", id);
+            }
+            if !xattrs.exclude_reply_user_ids.is_empty() {
+                tracing::info!(
+                    "dry-run: exclude_reply_user_ids={:?}",
+                    xattrs.exclude_reply_user_ids
+                );
+            }
+            if let Some(ref url) = xattrs.quote {
+                tracing::info!("dry-run: quote={}", url);
+            }
+            if let Some((lat, long)) = coordinates {
+                tracing::info!("dry-run: coordinates=({}, {})", lat, long);
+            }
+            if let Some(ref cw) = xattrs.content_warning {
+                tracing::info!("dry-run: content_warning={:?}", cw);
+            }
+            if let Some(ref lang) = xattrs.lang {
+                tracing::info!("dry-run: lang={}", lang);
+            }
+            if xattrs.pin {
+                tracing::info!("dry-run: pin");
+            }
+            if let Some(i) = xattrs.account {
+                tracing::info!("dry-run: account={}", self.accounts[i].name);
+            }
+            for chunk in &chunks {
+                tracing::info!("dry-run: status={:?}", chunk);
+            }
+            return Ok(());
+        }
+
+        let mut credentials = match self.credentials(ino) {
+            Some(credentials) => credentials,
+            None => {
+                let reason = "the inode is not associated with an account";
+                return Err(self.fail(libc::EBADF, reason.into()).await);
+            }
+        };
+
+        // The credentials are reloaded at most once per post.
+        let mut reloaded = false;
+
+        let mut media = self.media.lock().await;
+        let mut media_xattrs = self.media_xattrs.lock().await;
+        let mut staged = vec![];
+        for (data, media_xattrs) in media.iter().zip(media_xattrs.iter()) {
+            if data.is_empty() {
+                continue;
+            }
+            match sniff_media_type(data) {
+                Some(media_type) => staged.push((data, media_type, media_xattrs)),
+                None => {
+                    let reason = "unsupported media type";
+                    return Err(self.fail(libc::EINVAL, reason.into()).await);
+                }
+            }
+        }
+        // A video or an animated GIF cannot be attached with the other media.
+        if staged.len() > 1
+            && staged.iter().any(|(_, media_type, _)| {
+                *media_type == mime::IMAGE_GIF || media_type.type_() == mime::VIDEO
+            })
+        {
+            let reason = "a video or a GIF cannot be attached with the other media";
+            return Err(self.fail(libc::EINVAL, reason.into()).await);
+        }
+
+        let sensitive = staged
+            .iter()
+            .any(|(_, _, media_xattrs)| media_xattrs.sensitive);
+        let mut media_ids = Vec::with_capacity(staged.len());
+        for (data, media_type, media_xattrs) in staged {
+            let stripped = if self.config.strip_exif {
+                match strip_metadata(data, &media_type) {
+                    Ok(stripped) => stripped,
+                    Err(err) if self.config.strip_exif_strict => {
+                        let reason = format!("failed to strip the metadata of the media: {}", err);
+                        return Err(self.fail(libc::EINVAL, reason).await);
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "failed to strip the metadata of the media, uploading the original: {}",
+                            err
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let data = stripped.as_deref().unwrap_or(data);
+
+            loop {
+                // The upload may take long, waiting for the video to be processed.
+                match self
+                    .with_timeout_of(
+                        self.config.media_timeout,
+                        self.sink.upload_media(
+                            &credentials,
+                            data,
+                            media_type.clone(),
+                            media_xattrs.alt.as_deref(),
+                        ),
+                    )
+                    .await
+                {
+                    Ok(id) => {
+                        media_ids.push(id);
+                        break;
+                    }
+                    Err(err) => {
+                        if !reloaded && is_auth_error(&err) {
+                            reloaded = true;
+                            if let Some(new) = self.reload_credentials(ino) {
+                                credentials = new;
+                                continue;
+                            }
+                        }
+                        let reason = format!("failed to upload the media: {}", err);
+                        return Err(self.fail(tweet_errno(&err), reason).await);
+                    }
+                }
+            }
+        }
+
+        // Each chunk is posted as a reply to the previous one, and
+        // the media are attached to the first one. In the lines mode, the
+        // chunks are posted separately at an interval instead.
+        let mut in_reply_to = xattrs.in_reply_to;
+        let mut ids = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut opts = PostOptions {
+                text: chunk,
+                in_reply_to,
+                exclude_reply_user_ids: &xattrs.exclude_reply_user_ids,
+                content_warning: xattrs.content_warning.as_deref(),
+                language: xattrs.lang.as_deref(),
+                ..PostOptions::default()
+            };
+            if i == 0 {
+                opts.media_ids = &media_ids;
+                opts.sensitive = sensitive;
+                opts.coordinates = coordinates;
+                opts.quote = xattrs.quote.as_deref();
+            }
+
+            if self.config.lines_mode && i > 0 {
+                opts.in_reply_to = None;
+                tokio::time::delay_for(LINE_INTERVAL).await;
+            }
+
+            let mut res = self.send_tweet(&credentials, &opts).await;
+            if let Err(ref err) = res {
+                if !reloaded && is_auth_error(err) {
+                    reloaded = true;
+                    if let Some(new) = self.reload_credentials(ino) {
+                        credentials = new;
+                        res = self.send_tweet(&credentials, &opts).await;
+                    }
+                }
+            }
+            match res {
+                Ok(res) => {
+                    let id = res.response;
+                    tracing::info!(tweet_id = id, len = chunk.len(), "posted the tweet");
+                    in_reply_to = Some(id);
+                    ids.push(id);
+                }
+                Err(err) if i == 0 => {
+                    let reason = format!("failed to post the tweet: {}", err);
+                    return Err(self.fail(tweet_errno(&err), reason).await);
+                }
+                Err(err) => {
+                    // The posted tweets cannot be rolled back, so keep the rest
+                    // of the thread for recovering it via the read-back.
+                    let unposted = chunks[i..].join("\n");
+                    tracing::error!(
+                        ?ids,
+                        unposted = unposted.as_str(),
+                        error = %err,
+                        "failed to post the thread: posted {} of {}",
+                        i,
+                        chunks.len()
+                    );
+                    *media = Default::default();
+                    *media_xattrs = Default::default();
+                    *self.last_tweet.lock().await = in_reply_to.map(|id| (ino, id));
+                    *self.last_thread.lock().await = ids;
+                    *self.unposted.lock().await = Some(unposted);
+                    *self.last_error.lock().await = Some(format!(
+                        "failed to post the thread: posted {} of {}: {}",
+                        i,
+                        chunks.len(),
+                        err
+                    ));
+                    return Err(tweet_errno(&err));
+                }
+            }
+        }
+        *media = Default::default();
+        *media_xattrs = Default::default();
+
+        // The status is already posted, so the failure to pin it is not reported.
+        if let (true, Some(&id)) = (xattrs.pin, ids.first()) {
+            match self.with_timeout(self.sink.pin(&credentials, id)).await {
+                Ok(()) => tracing::info!(tweet_id = id, "pinned the tweet"),
+                Err(err) => tracing::warn!("failed to pin the tweet {}: {}", id, err),
+            }
+        }
+
+        *self.last_tweet.lock().await = in_reply_to.map(|id| (ino, id));
+        *self.last_thread.lock().await = ids;
+        *self.last_status.lock().await = Some(status);
+        *self.unposted.lock().await = None;
+        *self.last_error.lock().await = None;
+
+        Ok(())
+    }
+
+    /// Log the reason of the failed post, and keep it for the last_error file.
+    async fn fail(&self, errno: libc::c_int, reason: String) -> libc::c_int {
+        tracing::error!("{}", reason);
+        *self.last_error.lock().await = Some(reason);
+        errno
+    }
+
+    /// Post or schedule the status written to the inode, updating the stats.
+    ///
+    /// The empty content (e.g. by `touch`) is skipped without posting anything.
+    async fn submit(&self, ino: u64, content: Vec<u8>) -> Result<(), libc::c_int> {
+        let empty = if self.config.trim {
+            String::from_utf8_lossy(&content).trim().is_empty()
+        } else {
+            content.is_empty()
+        };
+        if empty {
+            tracing::debug!(ino, "skip the empty buffer");
+            return Ok(());
+        }
+
+        let mut xattrs = self.take_xattrs(ino).await;
+        let last_read = *self.last_read.lock().unwrap();
+        if ino == QUOTE_LAST_INO {
+            let id = match last_read {
+                Some(id) => id,
+                None => {
+                    let reason = "no timeline item has been read to quote";
+                    return Err(self.fail(libc::ENOENT, reason.into()).await);
+                }
+            };
+            match self.quote_url(ino, id).await {
+                Some(url) => xattrs.quote = Some(url),
+                None => {
+                    let reason = format!("failed to resolve the last read tweet {}", id);
+                    return Err(self.fail(libc::EINVAL, reason).await);
+                }
+            }
+            // Twitter does not allow a quote tweet to be a reply.
+            xattrs.in_reply_to = None;
+        }
+
+        // The status is confirmed as posted, i.e. with the template and the signature
+        // applied and split into the thread.
+        if let Some(ref fifo) = self.config.confirm_fifo {
+            match self.confirm(fifo, self.preview(&content).as_bytes()).await {
+                Ok(true) => tracing::info!("the status is confirmed"),
+                Ok(false) => {
+                    tracing::info!("the status is rejected via the FIFO, and discarded");
+                    return Ok(());
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => {
+                    let reason = "the status is not confirmed in time, and discarded";
+                    return Err(self.fail(libc::ETIMEDOUT, reason.into()).await);
+                }
+                Err(err) => {
+                    let reason = format!("failed to confirm the status: {}", err);
+                    return Err(self.fail(libc::EIO, reason).await);
+                }
+            }
+        }
+
+        if let Some(at) = xattrs.schedule {
+            if at > Utc::now() {
+                self.schedule(at, ino, content, xattrs).await;
+                return Ok(());
+            }
+        }
+
+        self.post_counted(ino, &content, xattrs).await?;
+        // The quoted item is consumed, unless another one is read meanwhile.
+        if ino == QUOTE_LAST_INO {
+            let mut current = self.last_read.lock().unwrap();
+            if *current == last_read {
+                *current = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Post the status, counting it as posted or failed in the stats.
+    async fn post_counted(
+        &self,
+        ino: u64,
+        content: &[u8],
+        xattrs: Xattrs,
+    ) -> Result<(), libc::c_int> {
+        let res = self.post(ino, content, xattrs).await;
+        match res {
+            Ok(()) => self.posted.fetch_add(1, Ordering::Relaxed),
+            Err(..) => self.failures.fetch_add(1, Ordering::Relaxed),
+        };
+        res
+    }
+
+    /// Ask whether to post the status via the FIFO, which is answered by the
+    /// external process with a `y` or `n` line within the timeout.
+    ///
+    /// The status is written to the FIFO until EOF, and then the answer is
+    /// read from the same FIFO.
+    async fn confirm(&self, fifo: &Path, status: &[u8]) -> io::Result<bool> {
+        use std::{
+            io::{Read, Write},
+            os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+        };
+
+        let _confirming = self.confirming.lock().await;
+        let deadline = Instant::now() + self.config.timeout;
+        let wait = || async {
+            if Instant::now() >= deadline {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            tokio::time::delay_for(CONFIRM_POLL_INTERVAL).await;
+            Ok(())
+        };
+        let open = |write: bool| {
+            std::fs::OpenOptions::new()
+                .read(!write)
+                .write(write)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(fifo)
+        };
+
+        // Opening for writing fails with ENXIO until the other end is opened.
+        let mut writer = loop {
+            match open(true) {
+                Ok(writer) => break writer,
+                Err(ref err) if err.raw_os_error() == Some(libc::ENXIO) => wait().await?,
+                Err(err) => return Err(err),
+            }
+        };
+        let mut written = 0;
+        while written < status.len() {
+            match writer.write(&status[written..]) {
+                Ok(n) => written += n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => wait().await?,
+                Err(err) => return Err(err),
+            }
+        }
+        // The FIFO is closed once the status is read entirely, so that the
+        // answer is not mixed with the rest of it.
+        loop {
+            let mut pending: libc::c_int = 0;
+            if unsafe { libc::ioctl(writer.as_raw_fd(), libc::FIONREAD, &mut pending) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if pending == 0 {
+                break;
+            }
+            wait().await?;
+        }
+        drop(writer);
+
+        // Reading returns EOF until the other end is opened for writing.
+        let mut reader = open(false)?;
+        let mut answer = vec![];
+        let mut buf = [0; 64];
+        while !answer.contains(&b'\n') {
+            match reader.read(&mut buf) {
+                Ok(0) if !answer.is_empty() => break,
+                Ok(0) => wait().await?,
+                Ok(n) => answer.extend_from_slice(&buf[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => wait().await?,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let answer = String::from_utf8_lossy(&answer);
+        let answer = answer.lines().next().unwrap_or_default().trim();
+        Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+    }
+
+    /// Resolve the URL of the quoted tweet.
+    async fn quote_url(&self, ino: u64, id: u64) -> Option<String> {
+        use futures::compat::Future01CompatExt;
+
+        if self.config.dry_run {
+            return Some(format!("https://twitter.com/i/web/status/{}", id));
+        }
+        // The other backends have no API of Twitter to look up the status.
+        if self.config.backend != Backend::Twitter {
+            return Some(self.sink.status_url(id));
+        }
+
+        let credentials = self.credentials(ino)?;
+        let token = credentials.read_token();
+        let res = egg_mode::tweet::show(id, token).compat().await;
+        tracing::debug!(result = ?res, "show result");
+
+        match res {
+            Ok(tweet) => {
+                let screen_name = &tweet.user.as_ref()?.screen_name;
+                Some(format!("https://twitter.com/{}/status/{}", screen_name, id))
+            }
+            Err(err) => {
+                tracing::error!("failed to resolve the quoted tweet: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Reject the endpoints requiring the user context (e.g. the home timeline)
+    /// if only the bearer token is given, rather than calling them in vain.
+    fn check_user_context(&self) -> Result<(), libc::c_int> {
+        if self.read_only {
+            tracing::debug!("the user context is required, but only the bearer token is given");
+            return Err(libc::EACCES);
+        }
+        Ok(())
+    }
+
+    /// Return the screen name of the default account.
+    ///
+    /// The credentials are verified only once, and the result is cached.
+    async fn whoami(&self) -> Result<String, libc::c_int> {
+        let mut screen_name = self.screen_name.lock().await;
+        if let Some(ref screen_name) = *screen_name {
+            return Ok(screen_name.clone());
+        }
+
+        self.check_user_context()?;
+        match self.sink.verify(&self.default_credentials()).await {
+            Ok(res) => {
+                *screen_name = Some(res.response.clone());
+                Ok(res.response)
+            }
+            Err(err) => {
+                tracing::error!("failed to verify the credentials: {}", err);
+                Err(libc::EACCES)
+            }
+        }
+    }
+
+    /// Delete the last posted tweet.
+    async fn undo<T>(&self, cx: &mut Context<'_, T>) -> io::Result<()>
+    where
+        T: ?Sized + Writer + Unpin,
+    {
+        let mut last_tweet = self.last_tweet.lock().await;
+        let (ino, id) = match *last_tweet {
+            Some(last_tweet) => last_tweet,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+
+        let credentials = match self.credentials(ino) {
+            Some(credentials) => credentials,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+
+        match self.sink.delete(&credentials, id).await {
+            Ok(..) => {
+                tracing::info!("deleted the tweet {}", id);
+                *last_tweet = None;
+                self.last_thread.lock().await.retain(|&i| i != id);
+                cx.reply(()).await
+            }
+            Err(err) => {
+                tracing::error!("failed to delete the tweet: {}", err);
+                cx.reply_err(tweet_errno(&err)).await
+            }
+        }
+    }
+
+    /// Return the home timeline of the default account.
+    ///
+    /// The timeline is cached, and fetched again after `timeline_ttl`.
+    async fn timeline(&self) -> Result<Vec<Status>, libc::c_int> {
+        self.check_user_context()?;
+        let mut timeline = self.timeline.lock().await;
+        if let Some((fetched, ref statuses)) = *timeline {
+            if fetched.elapsed() < self.config.timeline_ttl {
+                return Ok(statuses.clone());
+            }
+        }
+
+        let res = self
+            .with_timeout(
+                self.sink
+                    .home_timeline(&self.default_credentials(), self.config.timeline_count),
+            )
+            .await;
+        match res {
+            Ok(statuses) => {
+                tracing::debug!("fetched {} statuses in the timeline", statuses.len());
+                *timeline = Some((Instant::now(), statuses.clone()));
+                Ok(statuses)
+            }
+            Err(err) => {
+                tracing::error!("failed to fetch the timeline: {}", err);
+                Err(tweet_errno(&err))
+            }
+        }
+    }
+
+    /// Render the mentions of the default account, one per line from the oldest,
+    /// so that the new ones are appended to the end.
+    ///
+    /// The mentions are cached as the timeline.
+    async fn mentions(&self) -> Result<String, libc::c_int> {
+        self.check_user_context()?;
+        let mut mentions = self.mentions.lock().await;
+        let statuses = match *mentions {
+            Some((fetched, ref statuses)) if fetched.elapsed() < self.config.timeline_ttl => {
+                statuses
+            }
+            _ => {
+                let res = self
+                    .with_timeout(
+                        self.sink
+                            .mentions(&self.default_credentials(), self.config.timeline_count),
+                    )
+                    .await;
+                match res {
+                    Ok(statuses) => {
+                        tracing::debug!("fetched {} mentions", statuses.len());
+                        &mentions.insert((Instant::now(), statuses)).1
+                    }
+                    Err(err) => {
+                        tracing::error!("failed to fetch the mentions: {}", err);
+                        return Err(tweet_errno(&err));
+                    }
+                }
+            }
+        };
+        Ok(format_mentions(statuses))
+    }
+
+    /// Return the search results of the query, which are fetched again once
+    /// they are older than `timeline_ttl`.
+    ///
+    /// The query is the name of the directory, with the percent-encoded
+    /// characters decoded.
+    async fn search(&self, query: &str) -> Result<Vec<Status>, libc::c_int> {
+        let mut searches = self.searches.lock().await;
+        if let Some((fetched, statuses)) = searches.get(query) {
+            if fetched.elapsed() < self.config.timeline_ttl {
+                return Ok(statuses.clone());
+            }
+        }
+
+        let decoded = url::percent_encoding::percent_decode(query.as_bytes()).decode_utf8_lossy();
+        let count = self.config.timeline_count.min(MAX_SEARCH_COUNT);
+        let res = self
+            .with_timeout(
+                self.sink
+                    .search(&self.default_credentials(), &decoded, count),
+            )
+            .await;
+        match res {
+            Ok(statuses) => {
+                tracing::debug!("found {} statuses for {:?}", statuses.len(), decoded);
+                searches.insert(query.to_owned(), (Instant::now(), statuses.clone()));
+                Ok(statuses)
+            }
+            Err(err) => {
+                tracing::error!("failed to search {:?}: {}", decoded, err);
+                Err(tweet_errno(&err))
+            }
+        }
+    }
+
+    /// Return the inode of the query directory, allocating it on the first lookup.
+    ///
+    /// `None` is returned if `MAX_SEARCH_QUERIES` are already allocated.
+    fn search_ino(&self, query: &str) -> Option<u64> {
+        let mut queries = self.search_queries.lock().unwrap();
+        let i = match queries.iter().position(|q| q == query) {
+            Some(i) => i,
+            None if queries.len() >= MAX_SEARCH_QUERIES => return None,
+            None => {
+                queries.push(query.to_owned());
+                queries.len() - 1
+            }
+        };
+        Some(SEARCH_INO_BASE + ((i as u64) << 8))
+    }
+
+    /// Return the query of the query directory or the search result file,
+    /// along with the position of the result.
+    fn search_entry(&self, ino: u64) -> Option<(String, Option<usize>)> {
+        let offset = ino.checked_sub(SEARCH_INO_BASE)?;
+        let query = self
+            .search_queries
+            .lock()
+            .unwrap()
+            .get((offset >> 8) as usize)?
+            .clone();
+        match (offset & 0xff) as usize {
+            0 => Some((query, None)),
+            i if i <= self.config.timeline_count.min(MAX_SEARCH_COUNT) => {
+                Some((query, Some(i - 1)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the search result shown as the file, if the inode is the one.
+    async fn search_result(&self, ino: u64) -> Result<Option<Status>, libc::c_int> {
+        match self.search_entry(ino) {
+            Some((query, Some(i))) => Ok(self.search(&query).await?.get(i).cloned()),
+            _ => Ok(None),
+        }
+    }
+
+    /// Return whether the inode is a directory.
+    fn is_dir(&self, ino: u64) -> bool {
+        match ino {
+            ROOT_INO | TIMELINE_INO | DM_INO | SEARCH_INO => true,
+            ino => matches!(self.search_entry(ino), Some((_, None))),
+        }
+    }
+
+    /// Return the maximum size of the buffer of the inode.
+    fn max_buffer(&self, ino: u64) -> u64 {
+        match ino {
+            ino if media_slot(ino).is_some() => MAX_VIDEO_SIZE as u64,
+            _ => self.config.max_buffer,
+        }
+    }
+
+    /// Validate the buffered content of the file after a write, returning the errno
+    /// with which the write is rejected.
+    fn check_content(&self, ino: u64, content: &[u8]) -> Result<(), i32> {
+        if media_slot(ino).is_some() && content.len() > max_media_size(content) {
+            return Err(libc::EFBIG);
+        }
+        if self.dm_recipient(ino).is_some()
+            && String::from_utf8_lossy(content).chars().count() > MAX_DM_LEN
+        {
+            return Err(libc::EFBIG);
+        }
+        if self.credentials(ino).is_some() {
+            if self.config.strict_utf8 && !is_text(content) {
+                return Err(libc::EILSEQ);
+            }
+            // The whitespace to be trimmed is not counted.
+            let pending = if self.config.trim {
+                content.trim_ascii()
+            } else {
+                content
+            };
+            if !self.config.thread_mode
+                && !self.config.lines_mode
+                && pending_len(pending, self.config.count_mode) > self.max_len()
+            {
+                return Err(libc::EFBIG);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the inode of the recipient file in the DM directory, allocating it
+    /// on the first lookup.
+    ///
+    /// `None` is returned if `MAX_DM_RECIPIENTS` are already allocated.
+    fn dm_ino(&self, recipient: &str) -> Option<u64> {
+        let mut recipients = self.dm_recipients.lock().unwrap();
+        let i = match recipients.iter().position(|r| r == recipient) {
+            Some(i) => i,
+            None if recipients.len() >= MAX_DM_RECIPIENTS => return None,
+            None => {
+                recipients.push(recipient.to_owned());
+                recipients.len() - 1
+            }
+        };
+        Some(DM_INO_BASE + i as u64)
+    }
+
+    /// Return the recipient of the direct messages written to the inode.
+    fn dm_recipient(&self, ino: u64) -> Option<String> {
+        let i = ino.checked_sub(DM_INO_BASE)?;
+        self.dm_recipients.lock().unwrap().get(i as usize).cloned()
+    }
+
+    /// Send the direct message to the recipient.
+    ///
+    /// The recipient is given by the user ID or the screen name, which is
+    /// resolved only once.
+    async fn send_dm(&self, recipient: &str, content: &[u8]) -> Result<(), libc::c_int> {
+        let text = String::from_utf8_lossy(content);
+        if text.trim().is_empty() {
+            return Ok(());
+        }
+
+        let id = match recipient.parse() {
+            Ok(id) => id,
+            Err(..) => {
+                let mut user_ids = self.user_ids.lock().await;
+                match user_ids.get(recipient) {
+                    Some(&id) => id,
+                    None => {
+                        let res = self
+                            .with_timeout(self.sink.user_id(&self.default_credentials(), recipient))
+                            .await;
+                        match res {
+                            Ok(id) => *user_ids.entry(recipient.to_owned()).or_insert(id),
+                            Err(err) => {
+                                tracing::error!(
+                                    "failed to look up the user {}: {}",
+                                    recipient,
+                                    err
+                                );
+                                return Err(tweet_errno(&err));
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        if self.config.dry_run {
+            tracing::info!("dry-run: direct message to {}: {:?}", id, text);
+            return Ok(());
+        }
+
+        let res = self
+            .with_timeout(self.sink.send_dm(&self.default_credentials(), id, &text))
+            .await;
+        match res {
+            Ok(message_id) => {
+                tracing::info!(recipient = id, message_id, "sent the direct message");
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!("failed to send the direct message: {}", err);
+                Err(tweet_errno(&err))
+            }
+        }
+    }
+
+    /// Return the maximum length of a status, which is raised in the long-form mode.
+    fn max_len(&self) -> usize {
+        if self.config.longform {
+            LONGFORM_MAX_LEN
+        } else {
+            self.sink.max_len()
+        }
+    }
+
+    /// Return the position in the timeline of the timeline file.
+    fn timeline_index(&self, ino: u64) -> Option<usize> {
+        ino.checked_sub(TIMELINE_INO_BASE + 1)
+            .map(|i| i as usize)
+            .filter(|&i| i < self.config.timeline_count)
+    }
+
+    /// Format the report of the stats file.
+    async fn stats(&self) -> String {
+        let buffered: u64 = self
+            .files
+            .lock()
+            .await
+            .iter()
+            .map(|(_, file)| file.content.len())
+            .sum::<u64>()
+            + self
+                .media
+                .lock()
+                .await
+                .iter()
+                .map(|data| data.len() as u64)
+                .sum::<u64>();
+        format!(
+            "posted={}\nfailures={}\nlast_error={}\nbuffered_bytes={}\n",
+            self.posted.load(Ordering::Relaxed),
+            self.failures.load(Ordering::Relaxed),
+            self.last_error.lock().await.as_deref().unwrap_or(""),
+            buffered,
+        )
+    }
+
+    /// Render the effective configuration, with the credentials masked.
+    fn config(&self) -> String {
+        let secret = |value: &str| if value.is_empty() { "" } else { "***" };
+        let credentials = self.default_credentials();
+        let mut config =
+            format!(
+            "backend={}\nmax_len={}\npost_mode={}\ncount_mode={}\nlongform={}\nthread={}\nthread_numbering={}\n\
+             fsync_posts={}\ndry_run={}\npost_on_exit={}\nallow_duplicate={}\ntemplate={}\n\
+             trim={}\nsignature={}\nstrict_utf8={}\nstrip_exif={}\nmax_buffer={}\nspill_dir={}\n\
+             spill_threshold={}\nmode={:o}\nattr_ttl={:?}\ntimeout={:?}\nmedia_timeout={:?}\nconfirm_fifo={}\nretries={}\n\
+             retry_delay={:?}\nmin_interval={:?}\ntimeline_count={}\ntimeline_ttl={:?}\n\
+             read_only={}\nconsumer_key={}\nconsumer_secret={}\naccess_token={}\n\
+             access_token_secret={}\nbearer_token={}\n",
+            self.config.backend.name(),
+            self.max_len(),
+            if self.config.lines_mode { "lines" } else { "status" },
+            self.config.count_mode.name(),
+            self.config.longform,
+            self.config.thread_mode,
+            self.config.thread_numbering,
+            self.config.fsync_posts,
+            self.config.dry_run,
+            self.config.post_on_exit,
+            self.config.allow_duplicate,
+            self.config.template,
+            self.config.trim,
+            self.config.signature.as_deref().unwrap_or(""),
+            self.config.strict_utf8,
+            self.config.strip_exif,
+            self.config.max_buffer,
+            self.config.spill_dir.as_deref().map_or("".into(), Path::to_string_lossy),
+            self.config.spill_threshold,
+            self.config.mode,
+            self.config.attr_ttl,
+            self.config.timeout,
+            self.config.media_timeout,
+            self.config.confirm_fifo.as_deref().map_or("".into(), Path::to_string_lossy),
+            self.config.retries,
+            self.config.retry_delay,
+            self.config.min_interval.unwrap_or_default(),
+            self.config.timeline_count,
+            self.config.timeline_ttl,
+            self.read_only,
+            secret(&credentials.consumer_key),
+            secret(&credentials.consumer_secret),
+            secret(&credentials.access_token),
+            secret(&credentials.access_token_secret),
+            if credentials.has_bearer() { "***" } else { "" },
+        );
+        for account in &self.accounts {
+            config += &format!("account={}\n", account.name);
+        }
+        config
+    }
+
+    /// Like or unlike the status whose ID or URL is written.
+    #[cfg(feature = "reactions")]
+    async fn like(&self, content: &[u8], like: bool) -> Result<(), libc::c_int> {
+        let id = match parse_tweet_id(&String::from_utf8_lossy(content)) {
+            Some(id) => id,
+            None => return Err(libc::EINVAL),
+        };
+
+        let credentials = self.default_credentials();
+        let call = if like {
+            self.sink.like(&credentials, id)
+        } else {
+            self.sink.unlike(&credentials, id)
+        };
+        match self.with_timeout(call).await {
+            Ok(count) => {
+                tracing::info!(
+                    tweet_id = id,
+                    favorite_count = count,
+                    like,
+                    "reacted to the tweet"
+                );
+                *self.last_liked.lock().await = Some((id, count));
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!("failed to react to the tweet: {}", err);
+                Err(tweet_errno(&err))
+            }
+        }
+    }
+
+    /// Retweet the status whose ID or URL is written.
+    #[cfg(feature = "reactions")]
+    async fn retweet(&self, content: &[u8]) -> Result<(), libc::c_int> {
+        let id = match parse_tweet_id(&String::from_utf8_lossy(content)) {
+            Some(id) => id,
+            None => return Err(libc::EINVAL),
+        };
+
+        match self
+            .with_timeout(self.sink.retweet(&self.default_credentials(), id))
+            .await
+        {
+            Ok(retweet_id) => {
+                tracing::info!(tweet_id = id, retweet_id, "retweeted the tweet");
+                *self.last_retweet.lock().await = Some(retweet_id);
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!("failed to retweet the tweet: {}", err);
+                Err(tweet_errno(&err))
+            }
+        }
+    }
+
+    /// Return the entries in the root directory.
+    fn entries(&self) -> impl Iterator<Item = (&str, u64)> {
+        let accounts = self
+            .accounts
+            .iter()
+            .enumerate()
+            .map(|(i, account)| (account.name.as_str(), ACCOUNT_INO_BASE + i as u64));
+        ENTRIES.iter().copied().chain(accounts)
+    }
+
+    /// Return the credentials used to post the status written to the inode.
+    ///
+    /// The status, draft and quote-last files post from the default account,
+    /// and each account file from the corresponding account.
+    fn credentials(&self, ino: u64) -> Option<Arc<Credentials>> {
+        match ino {
+            STATUS_INO | DRAFT_INO | QUOTE_LAST_INO => Some(self.default_credentials()),
+            _ => ino
+                .checked_sub(ACCOUNT_INO_BASE)
+                .and_then(|i| self.accounts.get(i as usize))
+                .map(|account| account.credentials.clone()),
+        }
+    }
+
+    /// Return the credentials of the default account.
+    fn default_credentials(&self) -> Arc<Credentials> {
+        self.credentials.read().unwrap().clone()
+    }
+
+    /// Reload the credentials of the default account from the config file,
+    /// after they are rejected while mounted.
+    ///
+    /// The new credentials are returned only if they are changed.
+    fn reload_credentials(&self, ino: u64) -> Option<Arc<Credentials>> {
+        if !matches!(ino, STATUS_INO | DRAFT_INO | QUOTE_LAST_INO)
+            || self.config.backend != Backend::Twitter
+        {
+            return None;
+        }
+
+        tracing::warn!("the credentials are rejected, reloading them from the config file");
+        let reloaded = match config::load_credentials() {
+            Ok(reloaded) => reloaded,
+            Err(err) => {
+                tracing::error!("failed to reload the credentials: {}", err);
+                return None;
+            }
+        };
+
+        let mut credentials = self.credentials.write().unwrap();
+        let unchanged = reloaded.consumer_key == credentials.consumer_key
+            && reloaded.consumer_secret == credentials.consumer_secret
+            && reloaded.access_token == credentials.access_token
+            && reloaded.access_token_secret == credentials.access_token_secret;
+        if unchanged {
+            tracing::warn!("the reloaded credentials are unchanged");
+            return None;
+        }
+
+        tracing::info!("reloaded the credentials, retrying");
+        *credentials = Arc::new(reloaded);
+        Some(credentials.clone())
+    }
+
+    /// Check whether the inode can be opened with the flags.
+    fn check_open(&self, ino: u64, flags: u32) -> Result<(), libc::c_int> {
+        match ino {
+            UNDO_INO | WHOAMI_INO | STATS_INO | RATELIMIT_INO | LAST_ERROR_INO | CONFIG_INO
+            | MENTIONS_INO => (),
+            ino if media_slot(ino).is_some() => (),
+            #[cfg(feature = "reactions")]
+            LIKE_INO | UNLIKE_INO | RETWEET_INO => (),
+            ino if self.timeline_index(ino).is_some() => (),
+            #[cfg(feature = "poll")]
+            POLL_INO => (),
+            ino if self.dm_recipient(ino).is_some() => (),
+            ino if self.is_dir(ino) => return Err(libc::EISDIR),
+            ino if self.search_entry(ino).is_some() => (),
+            // Nothing can be quoted until a timeline item or the mentions are read.
+            QUOTE_LAST_INO if self.last_read.lock().unwrap().is_none() => return Err(libc::ENOENT),
+            ino if self.credentials(ino).is_some() => (),
+            _ => return Err(libc::ENOENT),
+        }
+
+        let write = flags as libc::c_int & libc::O_ACCMODE != libc::O_RDONLY;
+        if self.read_only && write {
+            return Err(libc::EROFS);
+        }
+        match flags as libc::c_int & libc::O_ACCMODE {
+            libc::O_WRONLY if self.is_writable(ino) => Ok(()),
+            libc::O_RDONLY if self.is_readable(ino) => Ok(()),
+            // for previewing the written content.
+            libc::O_RDWR if self.credentials(ino).is_some() => Ok(()),
+            // The virtual files showing the state are never written.
+            _ if write && !self.is_writable(ino) => Err(libc::EACCES),
+            _ => Err(libc::EPERM),
+        }
+    }
+
+    /// Return whether the content written to the inode is used, i.e. it is not
+    /// one of the virtual files showing the state.
+    fn is_writable(&self, ino: u64) -> bool {
+        !(ino == WHOAMI_INO
+            || ino == STATS_INO
+            || ino == RATELIMIT_INO
+            || ino == LAST_ERROR_INO
+            || ino == CONFIG_INO
+            || ino == MENTIONS_INO
+            || self.timeline_index(ino).is_some()
+            || self.search_entry(ino).is_some()
+            || self.is_dir(ino))
+    }
+
+    /// Return whether the inode can be read.
+    fn is_readable(&self, ino: u64) -> bool {
+        !self.is_writable(ino) || is_reaction(ino) || self.credentials(ino).is_some()
+    }
+
+    /// Return the size of the buffered content.
+    ///
+    /// Without the file handle, the largest content buffered for the inode
+    /// is reported, or 0 if nothing is buffered.
+    fn size(&self, files: &Files, ino: u64, fh: Option<u64>) -> u64 {
+        // The content posted by fsync is counted, so that the appended writes
+        // follow it.
+        if let Some(file) = fh.and_then(|fh| files.get(fh)) {
+            return file.synced_len + file.content.len();
+        }
+        files
+            .iter()
+            .filter(|(_, file)| file.ino == ino)
+            .map(|(_, file)| file.synced_len + file.content.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Return the size of the file reported by the attributes.
+    ///
+    /// The virtual files report their rendered content, and the others the
+    /// buffered content, or the staged one for the draft and the media files.
+    async fn file_size(&self, ino: u64, fh: Option<u64>) -> Result<u64, libc::c_int> {
+        if let Some(i) = self.timeline_index(ino) {
+            let timeline = self.timeline().await?;
+            return Ok(timeline
+                .get(i)
+                .map_or(0, |status| format_status(status).len() as u64));
+        }
+        if let Some(status) = self.search_result(ino).await? {
+            return Ok(format_status(&status).len() as u64);
+        }
+        let size = match ino {
+            // The handle reports its own buffer, which starts from the draft.
+            DRAFT_INO if fh.is_none() => self.draft.lock().await.len() as u64,
+            // The mentions are fetched only on read, so that a lookup or stat does
+            // not wait for the network. The content is read via direct I/O
+            // regardless of the size reported here.
+            MENTIONS_INO => self
+                .mentions
+                .lock()
+                .await
+                .as_ref()
+                .map_or(0, |(_, statuses)| format_mentions(statuses).len() as u64),
+            ino => {
+                let buffered = self.size(&*self.files.lock().await, ino, fh);
+                match media_slot(ino) {
+                    // The handle being written reports its own buffer.
+                    Some(slot) if fh.is_none() => {
+                        cmp::max(buffered, self.media.lock().await[slot].len() as u64)
+                    }
+                    _ => buffered,
+                }
+            }
+        };
+        Ok(size)
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let mut attr = FileAttr::default();
+        match ino {
+            ino if self.is_dir(ino) => {
+                attr.set_mode(libc::S_IFDIR | 0o500);
+                attr.set_nlink(2);
+            }
+            ino if ino == UNDO_INO || media_slot(ino).is_some() => {
+                attr.set_mode(libc::S_IFREG | 0o200);
+                attr.set_nlink(1);
+            }
+            WHOAMI_INO | STATS_INO | RATELIMIT_INO | LAST_ERROR_INO | CONFIG_INO | MENTIONS_INO => {
+                attr.set_mode(libc::S_IFREG | 0o400);
+                attr.set_nlink(1);
+            }
+            #[cfg(feature = "reactions")]
+            LIKE_INO | UNLIKE_INO | RETWEET_INO => {
+                attr.set_mode(libc::S_IFREG | 0o600);
+                attr.set_nlink(1);
+            }
+            ino if self.timeline_index(ino).is_some() => {
+                attr.set_mode(libc::S_IFREG | 0o400);
+                attr.set_nlink(1);
+            }
+            ino if self.dm_recipient(ino).is_some() => {
+                attr.set_mode(libc::S_IFREG | 0o200);
+                attr.set_nlink(1);
+            }
+            ino if self.search_entry(ino).is_some() => {
+                attr.set_mode(libc::S_IFREG | 0o400);
+                attr.set_nlink(1);
+            }
+            #[cfg(feature = "poll")]
+            POLL_INO => {
+                attr.set_mode(libc::S_IFREG | 0o200);
+                attr.set_nlink(1);
+            }
+            ino if self.credentials(ino).is_some() => {
+                attr.set_mode(libc::S_IFREG | self.config.mode);
+                attr.set_nlink(1);
+            }
+            _ => return None,
+        }
+        attr.set_ino(ino);
+        attr.set_uid(self.config.uid);
+        attr.set_gid(self.config.gid);
+        Some(attr)
+    }
+}
+
+#[polyfuse::async_trait]
+impl Filesystem for TweetFS {
+    async fn call<'a, 'cx, T>(
+        &'a self,
+        cx: &'a mut Context<'cx, T>,
+        op: Operation<'cx>,
+    ) -> io::Result<()>
+    where
+        T: ?Sized + Reader + Writer + Unpin + Send,
+    {
+        tracing::debug!("op={:?}", op);
+        match op {
+            Operation::Lookup(op) => {
+                let (ino, size) = match op.parent() {
+                    ROOT_INO => match self.entries().find(|&(name, _)| name == op.name()) {
+                        Some((_, ino)) => match self.file_size(ino, None).await {
+                            Ok(size) => (ino, size),
+                            Err(errno) => return cx.reply_err(errno).await,
+                        },
+                        None => return cx.reply_err(libc::ENOENT).await,
+                    },
+                    // The timeline files are named after the position, from 1.
+                    TIMELINE_INO => {
+                        let timeline = match self.timeline().await {
+                            Ok(timeline) => timeline,
+                            Err(errno) => return cx.reply_err(errno).await,
+                        };
+                        let i = op
+                            .name()
+                            .to_str()
+                            .and_then(|name| name.parse::<usize>().ok())
+                            .filter(|&i| i >= 1);
+                        match i.and_then(|i| Some((i, timeline.get(i - 1)?))) {
+                            Some((i, status)) => (
+                                TIMELINE_INO_BASE + i as u64,
+                                format_status(status).len() as u64,
+                            ),
+                            None => return cx.reply_err(libc::ENOENT).await,
+                        }
+                    }
+                    // Any user can be the recipient, named by the screen name or the ID.
+                    DM_INO => match op.name().to_str() {
+                        Some(name) if is_recipient_name(name) => match self.dm_ino(name) {
+                            Some(ino) => (ino, 0),
+                            None => return cx.reply_err(libc::ENOSPC).await,
+                        },
+                        _ => return cx.reply_err(libc::ENOENT).await,
+                    },
+                    // Any query can be looked up, and the results are fetched on demand.
+                    SEARCH_INO => match op.name().to_str() {
+                        Some(query) => match self.search_ino(query) {
+                            Some(ino) => (ino, 0),
+                            None => return cx.reply_err(libc::ENOSPC).await,
+                        },
+                        None => return cx.reply_err(libc::ENOENT).await,
+                    },
+                    // The result files are named after the position, as the timeline files.
+                    parent if self.is_dir(parent) => {
+                        let (query, _) = self.search_entry(parent).expect("not a query");
+                        let results = match self.search(&query).await {
+                            Ok(results) => results,
+                            Err(errno) => return cx.reply_err(errno).await,
+                        };
+                        let i = op
+                            .name()
+                            .to_str()
+                            .and_then(|name| name.parse::<usize>().ok())
+                            .filter(|&i| i >= 1);
+                        match i.and_then(|i| Some((i, results.get(i - 1)?))) {
+                            Some((i, status)) => {
+                                (parent + i as u64, format_status(status).len() as u64)
+                            }
+                            None => return cx.reply_err(libc::ENOENT).await,
+                        }
+                    }
+                    _ => return cx.reply_err(libc::ENOTDIR).await,
+                };
+                let mut attr = match self.attr(ino) {
+                    Some(attr) => attr,
+                    None => return cx.reply_err(libc::ENOENT).await,
+                };
+                attr.set_size(size);
+
+                cx.reply(
+                    ReplyEntry::default()
+                        .ino(ino)
+                        .attr(attr)
+                        .ttl_attr(self.config.attr_ttl)
+                        .ttl_entry(self.config.attr_ttl),
+                )
+                .await?;
+
+                Ok(())
+            }
+            Operation::Getattr(op) => {
+                let mut attr = match self.attr(op.ino()) {
+                    Some(attr) => attr,
+                    None => return cx.reply_err(libc::ENOENT).await,
+                };
+
+                match self.file_size(op.ino(), op.fh()).await {
+                    Ok(size) => attr.set_size(size),
+                    Err(errno) => return cx.reply_err(errno).await,
+                }
+
+                cx.reply(
+                    ReplyAttr::new(attr) //
+                        .ttl_attr(self.config.attr_ttl),
+                )
+                .await?;
+
+                Ok(())
+            }
+            Operation::Setattr(op) => {
+                let mut attr = match self.attr(op.ino()) {
+                    Some(attr) => attr,
+                    None => return cx.reply_err(libc::ENOENT).await,
+                };
+
+                // Only the size change is meaningful, and it applies to the
+                // buffered content. The truncation without a file handle
+                // (e.g. truncate(2)) affects all handles opened on the inode.
+                let mut files = self.files.lock().await;
+                if let Some(size) = op.size() {
+                    // The size is relative to the content posted by fsync, which
+                    // cannot be truncated. Checked on all handles before any of them
+                    // is truncated.
+                    for (fh, file) in files.iter() {
+                        if file.ino == op.ino() && op.fh().is_none_or(|f| f == fh) {
+                            match size.checked_sub(file.synced_len) {
+                                Some(len) if len > self.max_buffer(op.ino()) => {
+                                    return cx.reply_err(libc::EFBIG).await;
+                                }
+                                Some(..) => (),
+                                None => return cx.reply_err(libc::EINVAL).await,
+                            }
+                        }
+                    }
+                    for (fh, file) in files.iter_mut() {
+                        if file.ino == op.ino() && op.fh().is_none_or(|f| f == fh) {
+                            let len = size - file.synced_len;
+                            if let Err(err) = file.content.set_len(len) {
+                                tracing::error!("failed to truncate the buffer: {}", err);
+                                return cx.reply_err(libc::EIO).await;
+                            }
+                            file.written.truncate(len);
+                            file.modified = true;
+                        }
+                    }
+                }
+
+                attr.set_size(self.size(&files, op.ino(), op.fh()));
+                drop(files);
+
+                cx.reply(
+                    ReplyAttr::new(attr) //
+                        .ttl_attr(self.config.attr_ttl),
+                )
+                .await?;
+
+                Ok(())
+            }
+            Operation::Access(op) => {
+                let attr = match self.attr(op.ino()) {
+                    Some(attr) => attr,
+                    None => return cx.reply_err(libc::ENOENT).await,
+                };
+
+                if check_access(&attr, cx.uid(), cx.gid(), op.mask()) {
+                    cx.reply(()).await
+                } else {
+                    cx.reply_err(libc::EACCES).await
+                }
+            }
+            Operation::Opendir(op) => {
+                if !self.is_dir(op.ino()) {
+                    return cx.reply_err(libc::ENOTDIR).await;
+                }
+
+                let fh = self.dirs.lock().await.insert(op.ino()) as u64;
+
+                cx.reply(ReplyOpen::new(fh)).await
+            }
+            Operation::Releasedir(op) => {
+                let mut dirs = self.dirs.lock().await;
+                if !dirs.contains(op.fh() as usize) {
+                    return cx.reply_err(libc::EBADF).await;
+                }
+                dirs.remove(op.fh() as usize);
+
+                cx.reply(()).await
+            }
+            Operation::Readdir(op) => {
+                if !self.is_dir(op.ino()) {
+                    return cx.reply_err(libc::ENOTDIR).await;
+                }
+                if self.dirs.lock().await.get(op.fh() as usize) != Some(&op.ino()) {
+                    return cx.reply_err(libc::EBADF).await;
+                }
+
+                let names: Vec<(String, u64)> = match op.ino() {
+                    ROOT_INO => [(".", ROOT_INO), ("..", ROOT_INO)]
+                        .iter()
+                        .copied()
+                        .chain(self.entries())
+                        .map(|(name, ino)| (name.to_owned(), ino))
+                        .collect(),
+                    // The recipients looked up so far.
+                    DM_INO => [(".".to_owned(), DM_INO), ("..".to_owned(), ROOT_INO)]
+                        .iter()
+                        .cloned()
+                        .chain(
+                            self.dm_recipients
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .enumerate()
+                                .map(|(i, name)| (name.clone(), DM_INO_BASE + i as u64)),
+                        )
+                        .collect(),
+                    TIMELINE_INO => {
+                        let len = match self.timeline().await {
+                            Ok(timeline) => timeline.len(),
+                            Err(errno) => return cx.reply_err(errno).await,
+                        };
+                        [(".".to_owned(), TIMELINE_INO), ("..".to_owned(), ROOT_INO)]
+                            .iter()
+                            .cloned()
+                            .chain((1..=len).map(|i| (i.to_string(), TIMELINE_INO_BASE + i as u64)))
+                            .collect()
+                    }
+                    // The queries looked up so far.
+                    SEARCH_INO => {
+                        [(".".to_owned(), SEARCH_INO), ("..".to_owned(), ROOT_INO)]
+                            .iter()
+                            .cloned()
+                            .chain(self.search_queries.lock().unwrap().iter().enumerate().map(
+                                |(i, query)| (query.clone(), SEARCH_INO_BASE + ((i as u64) << 8)),
+                            ))
+                            .collect()
+                    }
+                    ino => {
+                        let (query, _) = self.search_entry(ino).expect("not a query");
+                        let len = match self.search(&query).await {
+                            Ok(results) => results.len(),
+                            Err(errno) => return cx.reply_err(errno).await,
+                        };
+                        [(".".to_owned(), ino), ("..".to_owned(), SEARCH_INO)]
+                            .iter()
+                            .cloned()
+                            .chain((1..=len).map(|i| (i.to_string(), ino + i as u64)))
+                            .collect()
+                    }
+                };
+                let entries = names.iter().enumerate().map(|(i, (name, ino))| {
+                    if self.is_dir(*ino) {
+                        DirEntry::dir(name, *ino, i as u64 + 1)
+                    } else {
+                        DirEntry::file(name, *ino, i as u64 + 1)
+                    }
+                });
+
+                let mut reply = vec![];
+                let mut total_len = 0;
+                for entry in entries.skip(op.offset() as usize) {
+                    let len = entry.as_ref().len();
+                    if total_len + len > op.size() as usize {
+                        break;
+                    }
+                    reply.push(entry);
+                    total_len += len;
+                }
+
+                cx.reply(reply).await?;
+                Ok(())
+            }
+            Operation::Open(op) => {
+                if let Err(errno) = self.check_open(op.ino(), op.flags()) {
+                    return cx.reply_err(errno).await;
+                }
+
+                let fh = self.open_file(op.ino()).await;
+
+                cx.reply(
+                    ReplyOpen::new(fh) //
+                        .direct_io(true)
+                        .keep_cache(false),
+                )
+                .await?;
+
+                Ok(())
+            }
+            // The entries cannot be created actually, but open(2) with O_CREAT
+            // may reach here if the entry is not cached by the kernel.
+            Operation::Create(op) => {
+                if op.parent() != ROOT_INO {
+                    return cx.reply_err(libc::ENOTDIR).await;
+                }
+
+                let ino = match self.entries().find(|&(name, _)| name == op.name()) {
+                    Some((_, ino)) => ino,
+                    None => return cx.reply_err(libc::EACCES).await,
+                };
+                if let Err(errno) = self.check_open(ino, op.open_flags()) {
+                    return cx.reply_err(errno).await;
+                }
+                let attr = self.attr(ino).expect("unknown entry");
+
+                let fh = self.open_file(ino).await;
+
+                let mut entry = ReplyEntry::default();
+                entry
+                    .ino(ino)
+                    .attr(attr)
+                    .ttl_attr(self.config.attr_ttl)
+                    .ttl_entry(self.config.attr_ttl);
+                let mut open = ReplyOpen::new(fh);
+                open.direct_io(true).keep_cache(false);
+                cx.reply((entry, open)).await?;
+
+                Ok(())
+            }
+            Operation::Write(op) => {
+                let mut files = self.files.lock().await;
+                let file = match files.get_mut(op.fh()) {
+                    Some(file) => file,
+                    None => return cx.reply_err(libc::EBADF).await,
+                };
+
+                // The handle is never opened for writing on the others, but checked
+                // again so that their contents are never buffered.
+                if !self.is_writable(file.ino) {
+                    return cx.reply_err(libc::EBADF).await;
+                }
+
+                // The content posted by fsync cannot be rewritten.
+                let offset = match op.offset().checked_sub(file.synced_len) {
+                    Some(offset) => offset,
+                    None => return cx.reply_err(libc::EINVAL).await,
+                };
+
+                // Checked before the buffer is extended to the offset.
+                if offset + op.size() as u64 > self.max_buffer(file.ino) {
+                    return cx.reply_err(libc::EFBIG).await;
+                }
+
+                let offset = offset as usize;
+                let size = op.size() as usize;
+                let mut data = vec![0; size];
+                // The interrupted reads are retried, rather than failing the write.
+                let res = {
+                    use futures::io::AsyncReadExt;
+                    let mut reader = cx.reader();
+                    let mut filled = 0;
+                    loop {
+                        if filled == size {
+                            break Ok(());
+                        }
+                        match reader.read(&mut data[filled..]).await {
+                            Ok(0) => break Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                            Ok(n) => filled += n,
+                            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {
+                                tracing::debug!("the read is interrupted, retrying")
+                            }
+                            Err(err) => break Err(err),
+                        }
+                    }
+                };
+                if let Err(err) = res {
+                    tracing::error!("failed to read the written data: {}", err);
+                    return cx.reply_err(libc::EIO).await;
+                }
+
+                match file.content {
+                    Buffer::Mem(ref mut content) => {
+                        // The content is updated in place, and the overwritten bytes are
+                        // kept so that the buffer is restored if the write is rejected.
+                        let len = content.len();
+                        let end = offset + size;
+                        let overwritten = content[offset.min(len)..end.min(len)].to_vec();
+                        if len < end {
+                            content.resize(end, 0);
+                        }
+                        content[offset..end].copy_from_slice(&data);
+
+                        if let Err(errno) = self.check_content(file.ino, content) {
+                            let start = offset.min(len);
+                            content[start..start + overwritten.len()].copy_from_slice(&overwritten);
+                            content.truncate(len);
+                            return cx.reply_err(errno).await;
+                        }
+
+                        if let Some(ref dir) = self.config.spill_dir {
+                            if self.credentials(file.ino).is_some()
+                                && file.content.len() > self.config.spill_threshold
+                            {
+                                match file.content.spill(dir) {
+                                    Ok(()) => {
+                                        tracing::debug!("spilled the buffer to {}", dir.display())
+                                    }
+                                    Err(err) => {
+                                        tracing::warn!("failed to spill the buffer: {}", err)
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    // The spilled content is validated on release and fsync instead.
+                    Buffer::File(..) => {
+                        if let Err(err) = file.content.write_at(offset as u64, &data) {
+                            tracing::error!("failed to write the spilled buffer: {}", err);
+                            return cx.reply_err(libc::EIO).await;
+                        }
+                    }
+                }
+
+                file.synced = false;
+                file.modified = true;
+                file.written.insert(offset as u64..(offset + size) as u64);
+
+                cx.reply(ReplyWrite::new(size as u32)).await?;
+                Ok(())
+            }
+            Operation::Read(op) => {
+                let content = match op.ino() {
+                    WHOAMI_INO => match self.whoami().await {
+                        Ok(screen_name) => format!("@{}\n", screen_name),
+                        Err(errno) => return cx.reply_err(errno).await,
+                    },
+                    STATS_INO => self.stats().await,
+                    CONFIG_INO => self.config(),
+                    // The offset is applied below, so that the new mentions
+                    // can be read by seeking to the previous end.
+                    MENTIONS_INO => match self.mentions().await {
+                        Ok(mentions) => {
+                            // The newest mention is shown last, and quoted by quote-last.
+                            if let Some((_, ref statuses)) = *self.mentions.lock().await {
+                                if let Some(status) = statuses.first() {
+                                    *self.last_read.lock().unwrap() = Some(status.id);
+                                }
+                            }
+                            mentions
+                        }
+                        Err(errno) => return cx.reply_err(errno).await,
+                    },
+                    RATELIMIT_INO => match *self.rate_limit.lock().await {
+                        Some((remaining, reset)) => format!(
+                            "remaining={} reset={}\n",
+                            remaining,
+                            DateTime::from_timestamp(i64::from(reset), 0)
+                                .unwrap_or_default()
+                                .to_rfc3339()
+                        ),
+                        None => "unknown\n".into(),
+                    },
+                    LAST_ERROR_INO => match *self.last_error.lock().await {
+                        Some(ref reason) => format!("{}\n", reason),
+                        None => String::new(),
+                    },
+                    #[cfg(feature = "reactions")]
+                    RETWEET_INO => match *self.last_retweet.lock().await {
+                        Some(id) => format!("{}\n", id),
+                        None => String::new(),
+                    },
+                    #[cfg(feature = "reactions")]
+                    LIKE_INO | UNLIKE_INO => match *self.last_liked.lock().await {
+                        Some((id, count)) => format!("id={}\nfavorite_count={}\n", id, count),
+                        None => String::new(),
+                    },
+                    ino if self.timeline_index(ino).is_some() => {
+                        let timeline = match self.timeline().await {
+                            Ok(timeline) => timeline,
+                            Err(errno) => return cx.reply_err(errno).await,
+                        };
+                        match self.timeline_index(ino).and_then(|i| timeline.get(i)) {
+                            Some(status) => {
+                                *self.last_read.lock().unwrap() = Some(status.id);
+                                format_status(status)
+                            }
+                            None => String::new(),
+                        }
+                    }
+                    ino if self.search_entry(ino).is_some() => {
+                        match self.search_result(ino).await {
+                            Ok(status) => status.as_ref().map(format_status).unwrap_or_default(),
+                            Err(errno) => return cx.reply_err(errno).await,
+                        }
+                    }
+                    DRAFT_INO => String::from_utf8_lossy(&self.draft.lock().await).into_owned(),
+                    ino if self.credentials(ino).is_some() => {
+                        // The handle with the written content previews the
+                        // tweets to be posted on release.
+                        let preview = match self.files.lock().await.get(op.fh()) {
+                            Some(file) if !file.content.is_empty() => match file.content.to_vec() {
+                                Ok(content) => Some(self.preview(&content)),
+                                Err(err) => {
+                                    tracing::error!("failed to read the spilled buffer: {}", err);
+                                    return cx.reply_err(libc::EIO).await;
+                                }
+                            },
+                            _ => None,
+                        };
+                        match preview {
+                            Some(preview) => preview,
+                            // Otherwise, the URLs of the tweets in the last thread,
+                            // one per line, followed by the rest failed to be posted.
+                            None => {
+                                let mut content: String = self
+                                    .last_thread
+                                    .lock()
+                                    .await
+                                    .iter()
+                                    .map(|&id| format!("{}\n", self.sink.status_url(id)))
+                                    .collect();
+                                if let Some(ref unposted) = *self.unposted.lock().await {
+                                    content += &format!("--- not posted ---\n{}\n", unposted);
+                                }
+                                content
+                            }
+                        }
+                    }
+                    _ => return cx.reply_err(libc::EBADF).await,
+                };
+
+                let offset = cmp::min(op.offset() as usize, content.len());
+                let end = cmp::min(offset + op.size() as usize, content.len());
+                cx.reply(&content.as_bytes()[offset..end]).await?;
+                Ok(())
+            }
+            Operation::Statfs(..) => {
+                // Nothing is stored actually, so report the fixed capacity.
+                let mut st = StatFs::default();
+                st.set_bsize(512);
+                st.set_frsize(512);
+                st.set_blocks(STATFS_BLOCKS);
+                st.set_bfree(STATFS_BLOCKS);
+                st.set_bavail(STATFS_BLOCKS);
+                st.set_files(1 + self.entries().count() as u64);
+                st.set_ffree(0);
+                st.set_namelen(255);
+
+                cx.reply(ReplyStatfs::new(st)).await
+            }
+            Operation::Getxattr(op) => {
+                if self.attr(op.ino()).is_none() {
+                    return cx.reply_err(libc::ENOENT).await;
+                }
+
+                let value = match op.name().to_str() {
+                    Some("user.tweet.remaining") if self.credentials(op.ino()).is_some() => {
+                        // Use the buffered content of the handle opened for writing,
+                        // if any.
+                        let files = self.files.lock().await;
+                        let len = files
+                            .iter()
+                            .filter(|(_, file)| file.ino == op.ino())
+                            // The spilled content is counted in bytes.
+                            .map(|(_, file)| {
+                                file.content
+                                    .as_mem()
+                                    .map_or(file.content.len() as usize, |content| {
+                                        pending_len(content, self.config.count_mode)
+                                    })
+                            })
+                            .max()
+                            .unwrap_or(0);
+                        (self.max_len() as i64 - len as i64).to_string()
+                    }
+                    Some("user.tweet.scheduled") if self.credentials(op.ino()).is_some() => {
+                        // The scheduled times of the pending tweets, one per line.
+                        let scheduled = self.scheduled.lock().await;
+                        let mut times: Vec<_> = scheduled
+                            .iter()
+                            .filter(|(_, &(_, ino))| ino == op.ino())
+                            .map(|(_, &(at, _))| at)
+                            .collect();
+                        times.sort();
+                        times
+                            .iter()
+                            .map(|at| format!("{}\n", at.to_rfc3339()))
+                            .collect()
+                    }
+                    _ => return cx.reply_err(libc::ENODATA).await,
+                };
+
+                match op.size() {
+                    0 => cx.reply(ReplyXattr::new(value.len() as u32)).await,
+                    size if (size as usize) < value.len() => cx.reply_err(libc::ERANGE).await,
+                    _ => cx.reply(value.as_bytes()).await,
+                }
+            }
+            Operation::Setxattr(op) => {
+                if self.attr(op.ino()).is_none() {
+                    return cx.reply_err(libc::ENOENT).await;
+                }
+                #[cfg(feature = "poll")]
+                {
+                    if op.ino() == POLL_INO {
+                        let duration = std::str::from_utf8(op.value())
+                            .ok()
+                            .and_then(poll::parse_duration);
+                        return match (op.name().to_str(), duration) {
+                            (Some("user.poll.duration_minutes"), Some(duration)) => {
+                                *self.poll_duration.lock().await = Some(duration);
+                                cx.reply(()).await
+                            }
+                            (Some("user.poll.duration_minutes"), None) => {
+                                cx.reply_err(libc::EINVAL).await
+                            }
+                            _ => cx.reply_err(libc::ENOTSUP).await,
+                        };
+                    }
+                }
+                if let Some(slot) = media_slot(op.ino()) {
+                    let value = match std::str::from_utf8(op.value()) {
+                        Ok(value) => value,
+                        Err(..) => return cx.reply_err(libc::EINVAL).await,
+                    };
+                    let media_xattrs = &mut self.media_xattrs.lock().await[slot];
+                    match op.name().to_str() {
+                        Some("user.media.alt") if value.chars().count() > MAX_ALT_TEXT_LEN => {
+                            return cx.reply_err(libc::EFBIG).await
+                        }
+                        Some("user.media.alt") => media_xattrs.alt = Some(value.to_owned()),
+                        Some("user.media.sensitive") => match parse_bool(value) {
+                            Some(sensitive) => media_xattrs.sensitive = sensitive,
+                            None => return cx.reply_err(libc::EINVAL).await,
+                        },
+                        _ => return cx.reply_err(libc::ENOTSUP).await,
+                    }
+                    return cx.reply(()).await;
+                }
+                if self.credentials(op.ino()).is_none() {
+                    return cx.reply_err(libc::ENOTSUP).await;
+                }
+
+                let value = match std::str::from_utf8(op.value()) {
+                    Ok(value) => value,
+                    Err(..) => return cx.reply_err(libc::EINVAL).await,
+                };
+
+                // The quoted tweet is resolved before locking the xattrs,
+                // since it requires calling the API.
+                let quote = match op.name().to_str() {
+                    Some("user.tweet.quote") => match parse_tweet_id(value) {
+                        Some(id) => match self.quote_url(op.ino(), id).await {
+                            Some(url) => Some(url),
+                            None => return cx.reply_err(libc::EINVAL).await,
+                        },
+                        None => return cx.reply_err(libc::EINVAL).await,
+                    },
+                    _ => None,
+                };
+
+                let mut xattrs = self.xattrs.lock().await;
+                let xattrs = xattrs.entry(op.ino()).or_default();
+                match op.name().to_str() {
+                    // Twitter does not allow a quote tweet to be a reply.
+                    Some("user.tweet.in_reply_to") if xattrs.quote.is_some() => {
+                        return cx.reply_err(libc::EINVAL).await
+                    }
+                    Some("user.tweet.quote") if xattrs.in_reply_to.is_some() => {
+                        return cx.reply_err(libc::EINVAL).await
+                    }
+                    Some("user.tweet.in_reply_to") => match parse_tweet_id(value) {
+                        Some(id) => xattrs.in_reply_to = Some(id),
+                        None => return cx.reply_err(libc::EINVAL).await,
+                    },
+                    Some("user.tweet.exclude_reply_user_ids") => match parse_user_ids(value) {
+                        Some(ids) => xattrs.exclude_reply_user_ids = ids,
+                        None => return cx.reply_err(libc::EINVAL).await,
+                    },
+                    Some("user.tweet.quote") => xattrs.quote = quote,
+                    Some("user.tweet.schedule") => match DateTime::parse_from_rfc3339(value.trim())
+                    {
+                        Ok(at) => xattrs.schedule = Some(at.with_timezone(&Utc)),
+                        Err(..) => return cx.reply_err(libc::EINVAL).await,
+                    },
+                    Some("user.tweet.lat") => match parse_coordinate(value, 90.0) {
+                        Some(lat) => xattrs.lat = Some(lat),
+                        None => return cx.reply_err(libc::EINVAL).await,
+                    },
+                    Some("user.tweet.long") => match parse_coordinate(value, 180.0) {
+                        Some(long) => xattrs.long = Some(long),
+                        None => return cx.reply_err(libc::EINVAL).await,
+                    },
+                    // The content warning is counted in the length of the status by Mastodon.
+                    Some("user.status.cw") if value.chars().count() > self.max_len() => {
+                        return cx.reply_err(libc::EFBIG).await
+                    }
+                    Some("user.status.cw") => xattrs.content_warning = Some(value.to_owned()),
+                    Some("user.tweet.lang") if !self.sink.supports_language() => {
+                        return cx.reply_err(libc::ENOTSUP).await
+                    }
+                    Some("user.tweet.lang") => match parse_language(value) {
+                        Some(lang) => xattrs.lang = Some(lang),
+                        None => return cx.reply_err(libc::EINVAL).await,
+                    },
+                    Some("user.tweet.pin") if !self.sink.supports_pin() => {
+                        return cx.reply_err(libc::ENOTSUP).await
+                    }
+                    Some("user.tweet.pin") => match parse_bool(value) {
+                        Some(pin) => xattrs.pin = pin,
+                        None => return cx.reply_err(libc::EINVAL).await,
+                    },
+                    // The other backends post with their own token, regardless of the account.
+                    Some("user.tweet.account") if self.config.backend != Backend::Twitter => {
+                        return cx.reply_err(libc::ENOTSUP).await;
+                    }
+                    Some("user.tweet.account") => {
+                        let name = value.trim();
+                        match self
+                            .accounts
+                            .iter()
+                            .position(|account| account.name == name)
+                        {
+                            Some(i) => xattrs.account = Some(i),
+                            None => return cx.reply_err(libc::ENOENT).await,
+                        }
+                    }
+                    _ => return cx.reply_err(libc::ENOTSUP).await,
+                }
+
+                cx.reply(()).await
+            }
+            // The entries cannot be removed actually, but unlinking some of
+            // them triggers the action instead.
+            Operation::Unlink(op) => {
+                if op.parent() != ROOT_INO {
+                    return cx.reply_err(libc::ENOTDIR).await;
+                }
+
+                match self.entries().find(|&(name, _)| name == op.name()) {
+                    Some((_, UNDO_INO)) => self.undo(cx).await,
+                    Some((_, ino)) if media_slot(ino).is_some() => {
+                        let slot = media_slot(ino).unwrap();
+                        self.media.lock().await[slot].clear();
+                        self.media_xattrs.lock().await[slot] = MediaXattrs::default();
+                        tracing::debug!(slot, "discarded the staged media");
+                        cx.reply(()).await
+                    }
+                    Some(..) => cx.reply_err(libc::EPERM).await,
+                    None => cx.reply_err(libc::ENOENT).await,
+                }
+            }
+            // Renaming the draft to `send` posts it, and to `discard` drops it.
+            Operation::Rename(op) => {
+                if op.parent() != ROOT_INO || op.name() != "draft" {
+                    return cx.reply_err(libc::EPERM).await;
+                }
+                if op.newparent() != ROOT_INO {
+                    return cx.reply_err(libc::EINVAL).await;
+                }
+
+                match op.newname().to_str() {
+                    Some("send") => {
+                        let content = self.draft.lock().await.clone();
+                        if content.is_empty() {
+                            return cx.reply_err(libc::ENOENT).await;
+                        }
+                        match self.submit(DRAFT_INO, content).await {
+                            Ok(()) => {
+                                self.draft.lock().await.clear();
+                                cx.reply(()).await
+                            }
+                            // The draft is kept for retrying.
+                            Err(errno) => cx.reply_err(errno).await,
+                        }
+                    }
+                    Some("discard") => {
+                        self.draft.lock().await.clear();
+                        self.take_xattrs(DRAFT_INO).await;
+                        tracing::debug!("discarded the draft");
+                        cx.reply(()).await
+                    }
+                    _ => cx.reply_err(libc::EINVAL).await,
+                }
+            }
+            // The tweet is posted only on release, so that the repeated flushes
+            // (e.g. on saving in editors) do not post the duplicated tweets.
+            Operation::Flush(..) => cx.reply(()).await,
+            Operation::Release(op) => {
+                let file = match self.files.lock().await.remove(op.fh()) {
+                    Some(file) => file,
+                    None => return cx.reply_err(libc::EBADF).await,
+                };
+
+                if op.flags() as libc::c_int & libc::O_ACCMODE == libc::O_RDONLY {
+                    return cx.reply(()).await;
+                }
+
+                let spilled = file.content.as_mem().is_none();
+                let content = match file.content.into_vec() {
+                    Ok(content) => content,
+                    Err(err) => {
+                        tracing::error!("failed to read the spilled buffer: {}", err);
+                        return cx.reply_err(libc::EIO).await;
+                    }
+                };
+                let content = match check_written(content, &file.written) {
+                    Ok(content) => content,
+                    Err(errno) => return cx.reply_err(errno).await,
+                };
+                // The spilled content is not checked on write, e.g. for the length.
+                if spilled {
+                    if let Err(errno) = self.check_content(file.ino, &content) {
+                        return cx.reply_err(errno).await;
+                    }
+                }
+
+                if let Some(slot) = media_slot(file.ino) {
+                    // An open/close without any write keeps the staged media.
+                    if !file.modified {
+                        return cx.reply(()).await;
+                    }
+                    // The media is attached to the next tweet.
+                    tracing::debug!(slot, "stage media: {} bytes", content.len());
+                    self.media.lock().await[slot] = content;
+                    return cx.reply(()).await;
+                }
+
+                if file.ino == DRAFT_INO {
+                    // The draft is kept until it is renamed.
+                    tracing::debug!("save the draft: {} bytes", content.len());
+                    *self.draft.lock().await = content;
+                    return cx.reply(()).await;
+                }
+
+                // Only the written content triggers the undo, so that merely opening
+                // the file (e.g. by an editor) does not delete the tweet.
+                if file.ino == UNDO_INO {
+                    if content.is_empty() {
+                        return cx.reply(()).await;
+                    }
+                    return self.undo(cx).await;
+                }
+
+                if let Some(recipient) = self.dm_recipient(file.ino) {
+                    return match self.send_dm(&recipient, &content).await {
+                        Ok(()) => cx.reply(()).await,
+                        Err(errno) => cx.reply_err(errno).await,
+                    };
+                }
+
+                #[cfg(feature = "reactions")]
+                {
+                    let res = match file.ino {
+                        LIKE_INO => Some(self.like(&content, true).await),
+                        UNLIKE_INO => Some(self.like(&content, false).await),
+                        RETWEET_INO => Some(self.retweet(&content).await),
+                        _ => None,
+                    };
+                    match res {
+                        Some(Ok(())) => return cx.reply(()).await,
+                        Some(Err(errno)) => return cx.reply_err(errno).await,
+                        None => (),
+                    }
+                }
+
+                #[cfg(feature = "poll")]
+                {
+                    if file.ino == POLL_INO {
+                        let content = String::from_utf8_lossy(&content);
+                        let poll = match poll::parse_poll(&content) {
+                            Some(poll) => poll,
+                            None => return cx.reply_err(libc::EINVAL).await,
+                        };
+                        let duration = self.poll_duration.lock().await.take();
+                        tracing::debug!(?poll, ?duration, "poll");
+
+                        // egg-mode does not support creating polls yet.
+                        tracing::error!("creating polls is not supported");
+                        return cx.reply_err(libc::ENOSYS).await;
+                    }
+                }
+
+                // The content has already been posted by fsync.
+                if file.synced {
+                    return cx.reply(()).await;
+                }
+
+                match self.submit(file.ino, content).await {
+                    Ok(()) => cx.reply(()).await,
+                    Err(errno) => cx.reply_err(errno).await,
+                }
+            }
+            Operation::Fsync(op) => {
+                if !self.config.fsync_posts
+                    || self.credentials(op.ino()).is_none()
+                    || op.ino() == DRAFT_INO
+                {
+                    return cx.reply(()).await;
+                }
+
+                // The buffer is copied here, and cleared only once the status is posted
+                // so that it is kept for the retry if posting fails.
+                let (content, written, spilled) = {
+                    let files = self.files.lock().await;
+                    let file = match files.get(op.fh()) {
+                        Some(file) => file,
+                        None => return cx.reply_err(libc::EBADF).await,
+                    };
+                    if file.synced {
+                        return cx.reply(()).await;
+                    }
+                    if file.written.contiguous_len().is_none() {
+                        tracing::error!("the written content has a gap");
+                        return cx.reply_err(libc::EIO).await;
+                    }
+                    let content = match file.content.to_vec() {
+                        Ok(content) => content,
+                        Err(err) => {
+                            tracing::error!("failed to read the spilled buffer: {}", err);
+                            return cx.reply_err(libc::EIO).await;
+                        }
+                    };
+                    (
+                        content,
+                        file.written.clone(),
+                        file.content.as_mem().is_none(),
+                    )
+                };
+                let len = content.len() as u64;
+                let content = match check_written(content, &written) {
+                    Ok(content) => content,
+                    Err(errno) => return cx.reply_err(errno).await,
+                };
+                // The spilled content is not checked on write, e.g. for the length.
+                if spilled {
+                    if let Err(errno) = self.check_content(op.ino(), &content) {
+                        return cx.reply_err(errno).await;
+                    }
+                }
+
+                if let Err(errno) = self.submit(op.ino(), content).await {
+                    return cx.reply_err(errno).await;
+                }
+
+                // The buffer is cleared, so that the handle is reused for the next status.
+                if let Some(file) = self.files.lock().await.get_mut(op.fh()) {
+                    file.synced = true;
+                    file.synced_len += len;
+                    file.content = Buffer::default();
+                    file.written = Ranges::default();
+                }
+                cx.reply(()).await
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Trim the tail of the content never written (e.g. extended by truncate(2)),
+/// and reject the content with a gap in the middle, rather than posting the NUL bytes.
+fn check_written(mut content: Vec<u8>, written: &Ranges) -> Result<Vec<u8>, libc::c_int> {
+    match written.contiguous_len() {
+        Some(len) if (len as usize) < content.len() => {
+            tracing::debug!("trim {} bytes never written", content.len() - len as usize);
+            content.truncate(len as usize);
+            Ok(content)
+        }
+        Some(..) => Ok(content),
+        None => {
+            tracing::error!("the written content has a gap");
+            Err(libc::EIO)
+        }
+    }
+}
+
+/// Return whether the inode is one of the like, unlike and retweet files.
+#[cfg(feature = "reactions")]
+fn is_reaction(ino: u64) -> bool {
+    matches!(ino, LIKE_INO | UNLIKE_INO | RETWEET_INO)
+}
+
+#[cfg(not(feature = "reactions"))]
+fn is_reaction(_: u64) -> bool {
+    false
+}
+
+/// Check whether the name is a valid screen name or user ID of the recipient.
+///
+/// The account of Mastodon (`user@host`) is also accepted.
+fn is_recipient_name(name: &str) -> bool {
+    !name.starts_with('.')
+        && !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '@' | '.' | '-'))
+}
+
+/// Format the status in the timeline file.
+fn format_status(status: &Status) -> String {
+    format!("@{} ({})\n{}\n", status.screen_name, status.id, status.text)
+}
+
+/// Render the mentions one per line from the oldest.
+fn format_mentions(statuses: &[Status]) -> String {
+    statuses
+        .iter()
+        .rev()
+        .map(|status| {
+            format!(
+                "@{}: {}\n",
+                status.screen_name,
+                status.text.replace('\n', " ")
+            )
+        })
+        .collect()
+}
+
+/// Check whether the user is permitted to access the file with `mask`.
+///
+/// The mounting user and root are always permitted, and the others are
+/// checked against the permission bits of the owner, group or others.
+fn check_access(attr: &FileAttr, uid: u32, gid: u32, mask: u32) -> bool {
+    let mask = mask & (libc::R_OK | libc::W_OK | libc::X_OK) as u32;
+    if mask == 0 || uid == 0 || uid == unsafe { libc::getuid() } {
+        return true;
+    }
+    let bits = if uid == attr.uid() {
+        attr.mode() >> 6
+    } else if gid == attr.gid() {
+        attr.mode() >> 3
+    } else {
+        attr.mode()
+    };
+    bits & mask == mask
+}
+
+/// Convert the reset time of the rate limit (in UNIX time) into an `Instant`.
+///
+/// The waiting time is capped at `MAX_RATE_LIMIT_WAIT`.
+fn reset_instant(reset: i32) -> Instant {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    let wait = Duration::from_secs(cmp::max(i64::from(reset) - now, 0) as u64);
+    Instant::now() + cmp::min(wait, MAX_RATE_LIMIT_WAIT)
+}
+
+/// Return whether the failed request is worth retrying.
+///
+/// The network errors, server-side errors and rate limits are considered
+/// transient, and the others (authentication, duplicated status, etc.) are not.
+fn is_retryable(err: &egg_mode::error::Error) -> bool {
+    use egg_mode::error::Error;
+    match err {
+        Error::NetError(..) | Error::IOError(..) | Error::RateLimit(..) => true,
+        Error::BadStatus(status) => status.is_server_error() || status.as_u16() == 429,
+        // rate limit exceeded / over capacity / internal error
+        Error::TwitterError(errors) => {
+            !errors.errors.is_empty()
+                && errors
+                    .errors
+                    .iter()
+                    .all(|e| e.code == 88 || e.code == 130 || e.code == 131)
+        }
+        _ => false,
+    }
+}
+
+/// Check whether the error is caused by the rejected credentials.
+fn is_auth_error(err: &egg_mode::error::Error) -> bool {
+    use egg_mode::error::Error;
+    match err {
+        Error::TwitterError(errors) => errors
+            .errors
+            .iter()
+            .any(|e| matches!(e.code, 32 | 89 | 135 | 215)),
+        Error::BadStatus(status) => status.as_u16() == 401,
+        _ => false,
+    }
+}
+
+/// Translate an error from egg-mode into the errno replied to the kernel.
+fn tweet_errno(err: &egg_mode::error::Error) -> i32 {
+    use egg_mode::error::Error;
+    match err {
+        Error::TwitterError(errors) => match errors.errors.first().map(|e| e.code) {
+            // could not authenticate / invalid or expired token / bad authentication data
+            Some(32) | Some(89) | Some(135) | Some(215) => libc::EACCES,
+            // the application or the account is suspended / not permitted to write
+            Some(64) | Some(261) | Some(326) => libc::EACCES,
+            // the recipient does not follow you / cannot receive the messages
+            Some(150) | Some(349) => libc::EACCES,
+            // user not found
+            Some(50) => libc::ENOENT,
+            // rate limit exceeded / over the daily status update limit
+            Some(88) | Some(185) => libc::EAGAIN,
+            // status is over the length limit
+            Some(186) => libc::EFBIG,
+            // status is a duplicate
+            Some(187) => libc::EEXIST,
+            // already favorited / already retweeted
+            Some(139) | Some(327) => libc::EALREADY,
+            // no status found with that ID
+            Some(144) => libc::ENOENT,
+            _ => libc::EIO,
+        },
+        Error::RateLimit(..) => libc::EAGAIN,
+        Error::IOError(err) if err.kind() == io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+        Error::BadStatus(status) => match status.as_u16() {
+            401 | 403 => libc::EACCES,
+            429 => libc::EAGAIN,
+            _ => libc::EIO,
+        },
+        _ => libc::EIO,
+    }
+}
+
+/// Parse the tweet ID, or the URL of the tweet.
+fn parse_tweet_id(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let id = match s.find("/status/") {
+        Some(pos) => {
+            let id = &s[pos + "/status/".len()..];
+            let end = id.find(|c: char| !c.is_ascii_digit()).unwrap_or(id.len());
+            &id[..end]
+        }
+        None => s,
+    };
+    id.parse().ok()
+}
+
+/// Parse the boolean value of an extended attribute.
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.trim() {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse the comma-separated list of the user IDs.
+fn parse_user_ids(s: &str) -> Option<Vec<u64>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Some(vec![]);
+    }
+    s.split(',').map(|id| id.trim().parse().ok()).collect()
+}
+
+/// Parse the latitude or longitude within `[-limit, limit]`.
+fn parse_coordinate(s: &str, limit: f64) -> Option<f64> {
+    s.trim()
+        .parse()
+        .ok()
+        .filter(|value: &f64| (-limit..=limit).contains(value))
+}
+
+/// Parse the ISO 639-1 code of the language, which is case-insensitive.
+fn parse_language(s: &str) -> Option<String> {
+    let lang = s.trim().to_ascii_lowercase();
+    if LANGUAGES.binary_search(&&*lang).is_ok() {
+        Some(lang)
+    } else {
+        None
+    }
+}
+
+/// Expand the placeholders `{{date}}`, `{{time}}` and `{{host}}` in the status.
+///
+/// The unknown placeholders are left verbatim, and `\{{` is expanded to a
+/// literal `{{`.
+fn expand_template<Tz>(s: &str, now: &DateTime<Tz>, host: &str) -> String
+where
+    Tz: chrono::TimeZone,
+    Tz::Offset: std::fmt::Display,
+{
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find("{{") {
+        if rest[..pos].ends_with('\\') {
+            expanded.push_str(&rest[..pos - 1]);
+            expanded.push_str("{{");
+            rest = &rest[pos + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        let end = match rest.find("}}") {
+            Some(end) => end + 2,
+            None => break,
+        };
+        match &rest[2..end - 2] {
+            // an unclosed brace followed by another placeholder.
+            name if name.contains("{{") => {
+                expanded.push_str("{{");
+                rest = &rest[2..];
+                continue;
+            }
+            "date" => expanded.push_str(&now.format("%Y-%m-%d").to_string()),
+            "time" => expanded.push_str(&now.format("%H:%M:%S").to_string()),
+            "host" => expanded.push_str(host),
+            _ => expanded.push_str(&rest[..end]),
+        }
+        rest = &rest[end..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Return the host name of the machine, or an empty string if unavailable.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// How the length of a status is counted, set by `TWEET_FS_COUNT_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CountMode {
+    Bytes,
+    Chars,
+    /// The weighted length counted by Twitter, which is the default.
+    Weighted,
+    /// The extended grapheme clusters, i.e. the characters as perceived.
+    Graphemes,
+}
+
+impl CountMode {
+    /// Return the name set by `TWEET_FS_COUNT_MODE`.
+    fn name(self) -> &'static str {
+        match self {
+            CountMode::Bytes => "bytes",
+            CountMode::Chars => "chars",
+            CountMode::Weighted => "weighted",
+            CountMode::Graphemes => "graphemes",
+        }
+    }
+
+    /// Count the length of the text.
+    fn len(self, s: &str) -> usize {
+        match self {
+            CountMode::Bytes => s.len(),
+            CountMode::Chars => s.chars().count(),
+            CountMode::Weighted => weighted_len(s),
+            CountMode::Graphemes => s.graphemes(true).count(),
+        }
+    }
+}
+
+/// Count the length of a status in the same manner as Twitter.
+///
+/// Each URL is counted as `URL_LEN` regardless of its actual length.
+fn weighted_len(s: &str) -> usize {
+    let text_len = |s: &str| s.chars().map(char_weight).sum::<usize>();
+    let mut len = 0;
+    let mut last = 0;
+    for url in url_regex().find_iter(s) {
+        len += text_len(&s[last..url.start()]) + URL_LEN;
+        last = url.end();
+    }
+    len + text_len(&s[last..])
+}
+
+/// Return the pattern of the URLs shortened by t.co.
+///
+/// The URL starts with the scheme or `www.`, and the trailing punctuation
+/// is not included.
+fn url_regex() -> &'static Regex {
+    static URL_REGEX: OnceLock<Regex> = OnceLock::new();
+    URL_REGEX.get_or_init(|| {
+        Regex::new(r#"(?i)\b(?:https?://|www\.)[^\s<>]*[^\s<>.,:;!?'"()\[\]]"#)
+            .expect("valid regex")
+    })
+}
+
+/// Return the weight of a character.
+///
+/// The characters in the ranges below (Latin, punctuations, etc.) are counted
+/// as 1 and the others (CJK, emoji, etc.) as 2.
+fn char_weight(c: char) -> usize {
+    match c as u32 {
+        0..=4351 | 8192..=8205 | 8208..=8223 | 8242..=8247 => 1,
+        _ => 2,
+    }
+}
+
+/// Count the length of the buffered content.
+///
+/// A multibyte character split at the end of the buffer is expected to be
+/// completed by the subsequent write, and hence is not counted.
+fn pending_len(content: &[u8], mode: CountMode) -> usize {
+    match std::str::from_utf8(content) {
+        Ok(s) => mode.len(s),
+        Err(err) if err.error_len().is_none() => {
+            mode.len(&String::from_utf8_lossy(&content[..err.valid_up_to()]))
+        }
+        Err(..) => mode.len(&String::from_utf8_lossy(content)),
+    }
+}
+
+/// Check if the buffered content is a valid UTF-8 text without control
+/// characters other than whitespace.
+///
+/// As in `pending_len`, a multibyte character split at the end is allowed.
+fn is_text(content: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(content) {
+        Ok(s) => s,
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&content[..err.valid_up_to()]).expect("valid UTF-8")
+        }
+        Err(..) => return false,
+    };
+    valid
+        .chars()
+        .all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t'))
+}
+
+/// Detect the media type of the staged media from its magic bytes.
+fn sniff_media_type(data: &[u8]) -> Option<mime::Mime> {
+    use egg_mode::media::media_types;
+    match data {
+        [0xFF, 0xD8, 0xFF, ..] => Some(media_types::image_jpg()),
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n', ..] => Some(media_types::image_png()),
+        [b'G', b'I', b'F', b'8', b'7', b'a', ..] | [b'G', b'I', b'F', b'8', b'9', b'a', ..] => {
+            Some(media_types::image_gif())
+        }
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+            Some(media_types::image_webp())
+        }
+        [_, _, _, _, b'f', b't', b'y', b'p', b0, b1, b2, b3, ..]
+            if MP4_BRANDS.contains(&&[*b0, *b1, *b2, *b3]) =>
+        {
+            Some(media_types::video_mp4())
+        }
+        _ => None,
+    }
+}
+
+/// Strip the metadata which may contain the location (EXIF, XMP, etc.)
+/// from the JPEG or PNG image.
+///
+/// The other media are returned as `None`, and uploaded as is. The stripped
+/// image is parsed again to check that it is still valid.
+fn strip_metadata(data: &[u8], media_type: &mime::Mime) -> anyhow::Result<Option<Vec<u8>>> {
+    use img_parts::{jpeg::Jpeg, png::Png, Bytes};
+
+    let stripped = if *media_type == mime::IMAGE_JPEG {
+        let mut jpeg = Jpeg::from_bytes(Bytes::copy_from_slice(data))?;
+        jpeg.segments_mut().retain(|segment| {
+            !matches!(
+                segment.marker(),
+                img_parts::jpeg::markers::APP1
+                    | img_parts::jpeg::markers::APP13
+                    | img_parts::jpeg::markers::COM
+            )
+        });
+        let stripped = jpeg.encoder().bytes();
+        Jpeg::from_bytes(stripped.clone())?;
+        stripped
+    } else if *media_type == mime::IMAGE_PNG {
+        let mut png = Png::from_bytes(Bytes::copy_from_slice(data))?;
+        png.chunks_mut().retain(|chunk| {
+            !matches!(
+                &chunk.kind(),
+                b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME"
+            )
+        });
+        let stripped = png.encoder().bytes();
+        Png::from_bytes(stripped.clone())?;
+        stripped
+    } else {
+        return Ok(None);
+    };
+    tracing::debug!(
+        "stripped {} bytes of the metadata",
+        data.len().saturating_sub(stripped.len())
+    );
+    Ok(Some(stripped.to_vec()))
+}
+
+/// Return the position of the media file in the attached media.
+fn media_slot(ino: u64) -> Option<usize> {
+    MEDIA_INOS.iter().position(|&media_ino| media_ino == ino)
+}
+
+/// Return the maximum size of the media, depending on the detected media type.
+///
+/// The largest limit is used until the media type is detected.
+fn max_media_size(data: &[u8]) -> usize {
+    match sniff_media_type(data) {
+        Some(media_type) if media_type == mime::IMAGE_GIF => MAX_GIF_SIZE,
+        Some(media_type) if media_type.type_() == mime::IMAGE => MAX_IMAGE_SIZE,
+        _ => MAX_VIDEO_SIZE,
+    }
+}
+
+/// Split a long status into the chunks to be posted as a thread.
+///
+/// Each chunk fits within `limit`, is split at a sentence or word boundary
+/// where possible and never contains an empty or whitespace-only text.
+fn split_thread(status: &str, limit: usize, mode: CountMode) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut rest = status.trim();
+    while !rest.is_empty() {
+        if mode.len(rest) <= limit {
+            chunks.push(rest.to_owned());
+            break;
+        }
+        let (head, tail) = rest.split_at(split_point(rest, limit, mode));
+        let head = head.trim_end();
+        if !head.is_empty() {
+            chunks.push(head.to_owned());
+        }
+        rest = tail.trim_start();
+    }
+    chunks
+}
+
+/// Split a long status into the chunks with the counter appended, e.g. " (1/3)".
+///
+/// The space for the counter is reserved in each chunk, which is widened
+/// until the number of the chunks fits in it. A status fitting in a single
+/// tweet is not numbered.
+fn split_numbered(status: &str, limit: usize, mode: CountMode) -> Vec<String> {
+    let status = status.trim();
+    if mode.len(status) <= limit {
+        return vec![status.to_owned()];
+    }
+
+    let mut total = 2;
+    loop {
+        let reserved = mode.len(&format!(" ({}/{})", total, total));
+        let chunks = split_thread(status, limit.saturating_sub(reserved), mode);
+        if chunks.len().to_string().len() <= total.to_string().len() {
+            let n = chunks.len();
+            return chunks
+                .into_iter()
+                .enumerate()
+                .map(|(i, chunk)| format!("{} ({}/{})", chunk, i + 1, n))
+                .collect();
+        }
+        total = chunks.len();
+    }
+}
+
+/// Find the byte offset at which the text is split so that the head fits within `limit`.
+///
+/// The last sentence boundary is preferred over the last word boundary, and
+/// the text is split at the last fitting character only if neither exists.
+fn split_point(s: &str, limit: usize, mode: CountMode) -> usize {
+    let mut sentence = None;
+    let mut word = None;
+    let mut hard = None;
+    let mut prev = None;
+    for (i, c) in s.char_indices() {
+        let end = i + c.len_utf8();
+        if c == '\n' || (c.is_whitespace() && matches!(prev, Some('.') | Some('!') | Some('?'))) {
+            sentence = Some(i);
+        } else if c.is_whitespace() {
+            word = Some(i);
+        }
+        // The whitespace right after the limit is still a boundary, since the
+        // head is split before it.
+        if mode.len(&s[..end]) > limit {
+            break;
+        }
+        if matches!(c, '。' | '！' | '？') {
+            sentence = Some(end);
+        }
+        hard = Some(end);
+        prev = Some(c);
+    }
+
+    sentence
+        .or(word)
+        .or(hard)
+        // always consume at least one character to make progress.
+        .unwrap_or_else(|| s.chars().next().map_or(s.len(), char::len_utf8))
 }
@@ -0,0 +1,120 @@
+//! Parsing the polls.
+
+/// The maximum number of options.
+const MAX_OPTIONS: usize = 4;
+
+/// The maximum length of an option.
+const MAX_OPTION_LEN: usize = 25;
+
+/// The duration of a poll must be between 5 minutes and 7 days.
+const DURATION_MINUTES: std::ops::RangeInclusive<u32> = 5..=7 * 24 * 60;
+
+/// A poll, written as the question on the first line followed by the options.
+#[derive(Debug)]
+#[allow(dead_code)] // not posted until egg-mode supports polls
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<String>,
+}
+
+/// Parse the poll, returning `None` if it is malformed.
+pub fn parse_poll(content: &str) -> Option<Poll> {
+    let mut lines = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    let question = lines.next()?.to_owned();
+    let options: Vec<String> = lines.map(ToOwned::to_owned).collect();
+
+    if options.len() < 2 || options.len() > MAX_OPTIONS {
+        return None;
+    }
+    if options
+        .iter()
+        .any(|option| option.chars().count() > MAX_OPTION_LEN)
+    {
+        return None;
+    }
+
+    Some(Poll { question, options })
+}
+
+/// Parse the duration of the poll in minutes.
+pub fn parse_duration(value: &str) -> Option<u32> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|minutes| DURATION_MINUTES.contains(minutes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(content: &str) -> Option<Vec<String>> {
+        parse_poll(content).map(|poll| poll.options)
+    }
+
+    #[test]
+    fn parse_valid_poll() {
+        let poll = parse_poll("Which one?\nA\nB").unwrap();
+        assert_eq!(poll.question, "Which one?");
+        assert_eq!(poll.options, ["A", "B"]);
+
+        // The blank lines and the surrounding whitespace are ignored.
+        let poll = parse_poll("  Which one?  \n\n A \n\n B \n").unwrap();
+        assert_eq!(poll.question, "Which one?");
+        assert_eq!(poll.options, ["A", "B"]);
+    }
+
+    #[test]
+    fn option_counts() {
+        let cases = &[
+            ("", None),
+            ("Which one?", None),
+            ("Which one?\nA", None),
+            ("Which one?\nA\nB\nC", Some(3)),
+            ("Which one?\nA\nB\nC\nD", Some(4)),
+            ("Which one?\nA\nB\nC\nD\nE", None),
+        ];
+        for &(content, count) in cases {
+            assert_eq!(options(content).map(|o| o.len()), count, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn option_lengths() {
+        let poll = |option: &str| options(&format!("Which one?\nA\n{}", option));
+        assert!(poll(&"a".repeat(25)).is_some());
+        assert!(poll(&"a".repeat(26)).is_none());
+        // counted in characters, not in bytes
+        assert!(poll(&"あ".repeat(25)).is_some());
+        assert!(poll(&"あ".repeat(26)).is_none());
+    }
+
+    #[test]
+    fn durations() {
+        let cases = &[
+            ("5", Some(5)),
+            ("60", Some(60)),
+            (" 60\n", Some(60)),
+            ("10080", Some(10080)),
+            // out of the range
+            ("4", None),
+            ("0", None),
+            ("10081", None),
+            // malformed
+            ("-5", None),
+            ("1.5", None),
+            ("60 minutes", None),
+            ("abc", None),
+            // missing
+            ("", None),
+            ("\n", None),
+        ];
+        for &(value, minutes) in cases {
+            assert_eq!(parse_duration(value), minutes, "{:?}", value);
+        }
+    }
+}
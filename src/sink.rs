@@ -0,0 +1,613 @@
+//! The backends posting the statuses.
+
+mod mastodon;
+
+pub use self::mastodon::MastodonSink;
+
+use crate::config::Credentials;
+use egg_mode::{error::Error, Response};
+use futures::compat::Future01CompatExt;
+use std::sync::{Arc, Mutex};
+
+/// The maximum weighted length of a tweet.
+const MAX_TWEET_LEN: usize = 280;
+
+/// The size of each segment appended in the chunked media upload.
+const MEDIA_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The maximum number of the times the media upload is resumed after a failure.
+const MAX_UPLOAD_RESUMES: u32 = 3;
+
+/// A status to be posted, along with its options.
+///
+/// Each backend maps the options it supports, and ignores the rest.
+#[derive(Debug, Default)]
+pub struct PostOptions<'a> {
+    pub text: &'a str,
+    pub in_reply_to: Option<u64>,
+    /// The users excluded from the mentions of the reply.
+    pub exclude_reply_user_ids: &'a [u64],
+    pub media_ids: &'a [u64],
+    pub coordinates: Option<(f64, f64)>,
+    /// Whether the attached media is sensitive.
+    pub sensitive: bool,
+    /// The URL of the quoted tweet.
+    pub quote: Option<&'a str>,
+    /// The content warning shown in place of the status, if supported.
+    pub content_warning: Option<&'a str>,
+    /// The ISO 639-1 code of the language of the status, if supported.
+    pub language: Option<&'a str>,
+}
+
+/// A status in the timeline.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub id: u64,
+    pub screen_name: String,
+    pub text: String,
+}
+
+impl From<egg_mode::tweet::Tweet> for Status {
+    fn from(tweet: egg_mode::tweet::Tweet) -> Self {
+        Self {
+            id: tweet.id,
+            screen_name: tweet.user.map(|user| user.screen_name).unwrap_or_default(),
+            text: tweet.text,
+        }
+    }
+}
+
+/// The backend posting the statuses.
+#[polyfuse::async_trait]
+pub trait TweetSink: Send + Sync {
+    /// Return the maximum length of a status.
+    fn max_len(&self) -> usize;
+
+    /// Return the URL of the posted status.
+    fn status_url(&self, id: u64) -> String {
+        format!("https://twitter.com/i/web/status/{}", id)
+    }
+
+    /// Return whether the language of the status can be set.
+    ///
+    /// Twitter detects the language by itself, and egg-mode has no parameter for it.
+    fn supports_language(&self) -> bool {
+        false
+    }
+
+    /// Return whether the long-form statuses (the note tweets) can be posted.
+    ///
+    /// egg-mode has no endpoint for the note tweets yet.
+    fn supports_longform(&self) -> bool {
+        false
+    }
+
+    /// Return whether the posted status can be pinned to the profile.
+    ///
+    /// Twitter has no public API for it, and egg-mode cannot sign a raw request.
+    fn supports_pin(&self) -> bool {
+        false
+    }
+
+    /// Pin the status to the profile of the account.
+    async fn pin(&self, _: &Credentials, _: u64) -> Result<(), Error> {
+        Err(Error::InvalidResponse("pinning is not supported", None))
+    }
+
+    /// Upload the media with the optional alt text and return its ID.
+    async fn upload_media(
+        &self,
+        credentials: &Credentials,
+        data: &[u8],
+        media_type: mime::Mime,
+        alt_text: Option<&str>,
+    ) -> Result<u64, Error>;
+
+    /// Post the status and return the ID of the posted tweet.
+    async fn post(
+        &self,
+        credentials: &Credentials,
+        opts: &PostOptions<'_>,
+    ) -> Result<Response<u64>, Error>;
+
+    /// Delete the posted tweet.
+    async fn delete(&self, credentials: &Credentials, id: u64) -> Result<(), Error>;
+
+    /// Verify the credentials and return the screen name of the account.
+    async fn verify(&self, credentials: &Credentials) -> Result<Response<String>, Error>;
+
+    /// Fetch the latest statuses in the home timeline.
+    async fn home_timeline(
+        &self,
+        credentials: &Credentials,
+        count: usize,
+    ) -> Result<Vec<Status>, Error>;
+
+    /// Fetch the recent statuses mentioning the user, from the newest.
+    async fn mentions(&self, credentials: &Credentials, count: usize)
+        -> Result<Vec<Status>, Error>;
+
+    /// Search the recent statuses matching the query.
+    async fn search(
+        &self,
+        credentials: &Credentials,
+        query: &str,
+        count: usize,
+    ) -> Result<Vec<Status>, Error>;
+
+    /// Look up the ID of the user by the screen name.
+    async fn user_id(&self, credentials: &Credentials, screen_name: &str) -> Result<u64, Error>;
+
+    /// Send the direct message to the user and return its ID.
+    async fn send_dm(
+        &self,
+        credentials: &Credentials,
+        recipient: u64,
+        text: &str,
+    ) -> Result<u64, Error>;
+
+    /// Like the status and return its new favorite count.
+    #[cfg(feature = "reactions")]
+    async fn like(&self, credentials: &Credentials, id: u64) -> Result<u64, Error>;
+
+    /// Unlike the status and return its new favorite count.
+    #[cfg(feature = "reactions")]
+    async fn unlike(&self, credentials: &Credentials, id: u64) -> Result<u64, Error>;
+
+    /// Retweet the status and return the ID of the retweet.
+    #[cfg(feature = "reactions")]
+    async fn retweet(&self, credentials: &Credentials, id: u64) -> Result<u64, Error>;
+}
+
+/// The backend posting to Twitter.
+#[derive(Debug)]
+pub struct TwitterSink;
+
+#[polyfuse::async_trait]
+impl TweetSink for TwitterSink {
+    fn max_len(&self) -> usize {
+        MAX_TWEET_LEN
+    }
+
+    async fn upload_media(
+        &self,
+        credentials: &Credentials,
+        data: &[u8],
+        media_type: mime::Mime,
+        alt_text: Option<&str>,
+    ) -> Result<u64, Error> {
+        use egg_mode::media::UploadState;
+
+        // The media is uploaded by INIT, APPEND of each segment and FINALIZE,
+        // and then the processing status is polled until it succeeds.
+        let mut builder = egg_mode::media::UploadBuilder::new(data.to_vec(), media_type)
+            .chunk_size(MEDIA_CHUNK_SIZE);
+        // The alt text is set via the metadata endpoint after the upload is finished.
+        if let Some(alt_text) = alt_text {
+            builder = builder.alt_text(alt_text.to_owned());
+        }
+        let mut upload = builder.call(credentials.token());
+
+        // The upload is resumed from the failed step, rather than uploading
+        // all the segments again.
+        let mut resumes = 0;
+        loop {
+            let res = (&mut upload).compat().await;
+            tracing::debug!(result = ?res, "upload result");
+            match res {
+                Ok(handle) => return Ok(handle.id),
+                Err(err) => match (err.state, err.error) {
+                    // The processing failure is not recovered by retrying.
+                    (_, err @ Error::MediaError(..)) | (UploadState::Complete, err) => {
+                        return Err(err)
+                    }
+                    (state, err) if resumes < MAX_UPLOAD_RESUMES => {
+                        tracing::warn!(
+                            "failed to upload the media at {:?}, resuming: {}",
+                            state,
+                            err
+                        );
+                        resumes += 1;
+                    }
+                    (_, err) => return Err(err),
+                },
+            }
+        }
+    }
+
+    async fn post(
+        &self,
+        credentials: &Credentials,
+        opts: &PostOptions<'_>,
+    ) -> Result<Response<u64>, Error> {
+        let tweet = draft_tweet(opts);
+        let res = tweet.send(credentials.token()).compat().await;
+        tracing::debug!(result = ?res, "tweet result");
+        res.map(|res| Response {
+            rate_limit: res.rate_limit,
+            rate_limit_remaining: res.rate_limit_remaining,
+            rate_limit_reset: res.rate_limit_reset,
+            response: res.id,
+        })
+    }
+
+    async fn delete(&self, credentials: &Credentials, id: u64) -> Result<(), Error> {
+        let res = egg_mode::tweet::delete(id, credentials.token())
+            .compat()
+            .await;
+        tracing::debug!(result = ?res, "delete result");
+        res.map(drop)
+    }
+
+    async fn verify(&self, credentials: &Credentials) -> Result<Response<String>, Error> {
+        let res = egg_mode::verify_tokens(credentials.read_token())
+            .compat()
+            .await;
+        tracing::debug!(result = ?res, "verify result");
+        res.map(|user| Response {
+            rate_limit: user.rate_limit,
+            rate_limit_remaining: user.rate_limit_remaining,
+            rate_limit_reset: user.rate_limit_reset,
+            response: user.response.screen_name,
+        })
+    }
+
+    async fn home_timeline(
+        &self,
+        credentials: &Credentials,
+        count: usize,
+    ) -> Result<Vec<Status>, Error> {
+        let res = egg_mode::tweet::home_timeline(credentials.read_token())
+            .with_page_size(count as i32)
+            .start()
+            .compat()
+            .await;
+        let (_, tweets) = res?;
+        Ok(tweets.response.into_iter().map(Status::from).collect())
+    }
+
+    async fn mentions(
+        &self,
+        credentials: &Credentials,
+        count: usize,
+    ) -> Result<Vec<Status>, Error> {
+        let res = egg_mode::tweet::mentions_timeline(credentials.read_token())
+            .with_page_size(count as i32)
+            .start()
+            .compat()
+            .await;
+        let (_, tweets) = res?;
+        Ok(tweets.response.into_iter().map(Status::from).collect())
+    }
+
+    async fn search(
+        &self,
+        credentials: &Credentials,
+        query: &str,
+        count: usize,
+    ) -> Result<Vec<Status>, Error> {
+        let res = egg_mode::search::search(query.to_owned())
+            .count(count as u32)
+            .call(credentials.read_token())
+            .compat()
+            .await;
+        let result = res?;
+        Ok(result
+            .response
+            .statuses
+            .into_iter()
+            .map(Status::from)
+            .collect())
+    }
+
+    async fn user_id(&self, credentials: &Credentials, screen_name: &str) -> Result<u64, Error> {
+        let res = egg_mode::user::show(screen_name, credentials.read_token())
+            .compat()
+            .await;
+        tracing::debug!(result = ?res, "user result");
+        res.map(|user| user.id)
+    }
+
+    async fn send_dm(
+        &self,
+        credentials: &Credentials,
+        recipient: u64,
+        text: &str,
+    ) -> Result<u64, Error> {
+        let res = egg_mode::direct::send(recipient, text, credentials.token())
+            .compat()
+            .await;
+        tracing::debug!(result = ?res, "direct message result");
+        res.map(|message| message.id)
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn like(&self, credentials: &Credentials, id: u64) -> Result<u64, Error> {
+        let res = egg_mode::tweet::like(id, credentials.token())
+            .compat()
+            .await;
+        tracing::debug!(result = ?res, "like result");
+        res.map(|tweet| tweet.favorite_count as u64)
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn unlike(&self, credentials: &Credentials, id: u64) -> Result<u64, Error> {
+        let res = egg_mode::tweet::unlike(id, credentials.token())
+            .compat()
+            .await;
+        tracing::debug!(result = ?res, "unlike result");
+        res.map(|tweet| tweet.favorite_count as u64)
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn retweet(&self, credentials: &Credentials, id: u64) -> Result<u64, Error> {
+        let res = egg_mode::tweet::retweet(id, credentials.token())
+            .compat()
+            .await;
+        tracing::debug!(result = ?res, "retweet result");
+        res.map(|tweet| tweet.id)
+    }
+}
+
+/// Map the options onto the draft tweet, ignoring the unsupported ones with a warning.
+fn draft_tweet<'a>(opts: &PostOptions<'a>) -> egg_mode::tweet::DraftTweet<'a> {
+    let mut tweet = egg_mode::tweet::DraftTweet::new(opts.text);
+    if let Some(id) = opts.in_reply_to {
+        tweet = tweet.in_reply_to(id).auto_populate_reply_metadata(true);
+        if !opts.exclude_reply_user_ids.is_empty() {
+            tweet = tweet.exclude_reply_user_ids(opts.exclude_reply_user_ids);
+        }
+    }
+    if !opts.media_ids.is_empty() {
+        tweet = tweet.media_ids(opts.media_ids);
+    }
+    if opts.sensitive {
+        tweet = tweet.possibly_sensitive(true);
+    }
+    if let Some((lat, long)) = opts.coordinates {
+        tweet = tweet.coordinates(lat, long, true);
+    }
+    if let Some(url) = opts.quote {
+        tweet = tweet.attachment_url(url);
+    }
+    if opts.content_warning.is_some() {
+        tracing::warn!("the content warning is not supported by Twitter");
+    }
+    tweet
+}
+
+/// The in-memory backend recording the posted statuses, without calling any API.
+#[derive(Debug, Default)]
+pub struct MockSink {
+    statuses: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockSink {
+    /// Return the statuses posted so far, which is shared with the sink.
+    #[cfg(test)]
+    pub fn statuses(&self) -> Arc<Mutex<Vec<String>>> {
+        self.statuses.clone()
+    }
+}
+
+#[polyfuse::async_trait]
+impl TweetSink for MockSink {
+    fn max_len(&self) -> usize {
+        MAX_TWEET_LEN
+    }
+
+    fn supports_language(&self) -> bool {
+        true
+    }
+
+    fn supports_longform(&self) -> bool {
+        true
+    }
+
+    fn supports_pin(&self) -> bool {
+        true
+    }
+
+    async fn pin(&self, _: &Credentials, id: u64) -> Result<(), Error> {
+        tracing::info!("mock: pin {}", id);
+        Ok(())
+    }
+
+    async fn upload_media(
+        &self,
+        _: &Credentials,
+        data: &[u8],
+        media_type: mime::Mime,
+        alt_text: Option<&str>,
+    ) -> Result<u64, Error> {
+        tracing::info!(
+            "mock: upload {} ({} bytes, alt = {:?})",
+            media_type,
+            data.len(),
+            alt_text
+        );
+        Ok(0)
+    }
+
+    async fn post(&self, _: &Credentials, opts: &PostOptions<'_>) -> Result<Response<u64>, Error> {
+        let mut statuses = self.statuses.lock().unwrap();
+        statuses.push(opts.text.to_owned());
+        let id = statuses.len() as u64;
+        tracing::info!("mock: post {}: {:?}", id, opts);
+
+        Ok(Response {
+            rate_limit: -1,
+            rate_limit_remaining: i32::MAX,
+            rate_limit_reset: -1,
+            response: id,
+        })
+    }
+
+    async fn delete(&self, _: &Credentials, id: u64) -> Result<(), Error> {
+        tracing::info!("mock: delete {}", id);
+        Ok(())
+    }
+
+    async fn verify(&self, _: &Credentials) -> Result<Response<String>, Error> {
+        Ok(Response {
+            rate_limit: -1,
+            rate_limit_remaining: i32::MAX,
+            rate_limit_reset: -1,
+            response: "mock".into(),
+        })
+    }
+
+    async fn home_timeline(&self, _: &Credentials, count: usize) -> Result<Vec<Status>, Error> {
+        let statuses = self.statuses.lock().unwrap();
+        Ok(statuses
+            .iter()
+            .enumerate()
+            .rev()
+            .take(count)
+            .map(|(i, status)| Status {
+                id: i as u64 + 1,
+                screen_name: "mock".into(),
+                text: status.clone(),
+            })
+            .collect())
+    }
+
+    /// The posted statuses containing a mention are returned.
+    async fn mentions(&self, _: &Credentials, count: usize) -> Result<Vec<Status>, Error> {
+        let statuses = self.statuses.lock().unwrap();
+        Ok(statuses
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, status)| status.contains('@'))
+            .take(count)
+            .map(|(i, status)| Status {
+                id: i as u64 + 1,
+                screen_name: "mock".into(),
+                text: status.clone(),
+            })
+            .collect())
+    }
+
+    async fn search(
+        &self,
+        _: &Credentials,
+        query: &str,
+        count: usize,
+    ) -> Result<Vec<Status>, Error> {
+        let statuses = self.statuses.lock().unwrap();
+        Ok(statuses
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, status)| status.contains(query))
+            .take(count)
+            .map(|(i, status)| Status {
+                id: i as u64 + 1,
+                screen_name: "mock".into(),
+                text: status.clone(),
+            })
+            .collect())
+    }
+
+    async fn user_id(&self, _: &Credentials, screen_name: &str) -> Result<u64, Error> {
+        tracing::info!("mock: user {}", screen_name);
+        Ok(1)
+    }
+
+    async fn send_dm(&self, _: &Credentials, recipient: u64, text: &str) -> Result<u64, Error> {
+        tracing::info!("mock: direct message to {}: {:?}", recipient, text);
+        Ok(1)
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn like(&self, _: &Credentials, id: u64) -> Result<u64, Error> {
+        tracing::info!("mock: like {}", id);
+        Ok(1)
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn unlike(&self, _: &Credentials, id: u64) -> Result<u64, Error> {
+        tracing::info!("mock: unlike {}", id);
+        Ok(0)
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn retweet(&self, _: &Credentials, id: u64) -> Result<u64, Error> {
+        let mut statuses = self.statuses.lock().unwrap();
+        statuses.push(format!("RT {}", id));
+        tracing::info!("mock: retweet {}", id);
+        Ok(statuses.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draft_text_only() {
+        let tweet = draft_tweet(&PostOptions {
+            text: "Hello",
+            ..Default::default()
+        });
+        assert_eq!(tweet.text, "Hello");
+        assert_eq!(tweet.in_reply_to, None);
+        assert_eq!(tweet.auto_populate_reply_metadata, None);
+        assert_eq!(tweet.exclude_reply_user_ids, None);
+        assert_eq!(tweet.attachment_url, None);
+        assert_eq!(tweet.coordinates, None);
+        assert_eq!(tweet.media_ids, [0; 4]);
+        assert_eq!(tweet.possibly_sensitive, None);
+    }
+
+    #[test]
+    fn draft_all_supported_options() {
+        let tweet = draft_tweet(&PostOptions {
+            text: "Hello",
+            in_reply_to: Some(42),
+            exclude_reply_user_ids: &[7, 8],
+            media_ids: &[1, 2],
+            coordinates: Some((35.0, 139.0)),
+            sensitive: true,
+            quote: Some("https://twitter.com/user/status/1"),
+            ..Default::default()
+        });
+        assert_eq!(tweet.in_reply_to, Some(42));
+        assert_eq!(tweet.auto_populate_reply_metadata, Some(true));
+        assert_eq!(tweet.exclude_reply_user_ids.as_deref(), Some(&[7, 8][..]));
+        assert_eq!(tweet.media_ids, [1, 2, 0, 0]);
+        assert_eq!(tweet.coordinates, Some((35.0, 139.0)));
+        assert_eq!(tweet.display_coordinates, Some(true));
+        assert_eq!(tweet.possibly_sensitive, Some(true));
+        assert_eq!(
+            tweet.attachment_url.as_deref(),
+            Some("https://twitter.com/user/status/1")
+        );
+    }
+
+    #[test]
+    fn draft_excluded_users_without_reply() {
+        // The excluded users only apply to a reply.
+        let tweet = draft_tweet(&PostOptions {
+            text: "Hello",
+            exclude_reply_user_ids: &[7, 8],
+            ..Default::default()
+        });
+        assert_eq!(tweet.auto_populate_reply_metadata, None);
+        assert_eq!(tweet.exclude_reply_user_ids, None);
+    }
+
+    #[test]
+    fn draft_unsupported_options_ignored() {
+        let tweet = draft_tweet(&PostOptions {
+            text: "Hello",
+            content_warning: Some("spoiler"),
+            language: Some("ja"),
+            ..Default::default()
+        });
+        assert_eq!(tweet.text, "Hello");
+        assert_eq!(tweet.possibly_sensitive, None);
+        assert_eq!(tweet.attachment_url, None);
+    }
+}
@@ -0,0 +1,458 @@
+//! The backend posting to Mastodon.
+
+use super::{PostOptions, Status, TweetSink};
+use crate::config::Credentials;
+use egg_mode::{error::Error, Response};
+use futures::{compat::Future01CompatExt, compat::Stream01CompatExt, TryStreamExt};
+use hyper::{client::HttpConnector, header, Body, Method, Request};
+use hyper_tls::HttpsConnector;
+use std::{env, time::Duration};
+
+/// The default maximum length of a status.
+const DEFAULT_MAX_LEN: usize = 500;
+
+/// The interval of polling the media being processed.
+const MEDIA_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The backend posting the statuses to a Mastodon instance.
+///
+/// The credentials of the filesystem are not used, and the access token
+/// is given by `MASTODON_ACCESS_TOKEN` instead.
+pub struct MastodonSink {
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    base_url: String,
+    access_token: String,
+    max_len: usize,
+}
+
+impl MastodonSink {
+    /// Create the backend configured by the environment variables.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let base_url = env::var("MASTODON_BASE_URL")
+            .map_err(|_| anyhow::anyhow!("missing MASTODON_BASE_URL"))?;
+        let base_url = base_url.trim_end_matches('/').to_owned();
+        base_url
+            .parse::<hyper::Uri>()
+            .map_err(|err| anyhow::anyhow!("invalid MASTODON_BASE_URL: {}", err))?;
+
+        let access_token = env::var("MASTODON_ACCESS_TOKEN")
+            .map_err(|_| anyhow::anyhow!("missing MASTODON_ACCESS_TOKEN"))?;
+
+        let max_len = match env::var("MASTODON_MAX_LEN") {
+            Ok(max_len) => max_len
+                .parse()
+                .map_err(|err| anyhow::anyhow!("invalid MASTODON_MAX_LEN: {}", err))?,
+            Err(..) => DEFAULT_MAX_LEN,
+        };
+
+        let connector = HttpsConnector::new(4)?;
+        Ok(Self {
+            client: hyper::Client::builder().build(connector),
+            base_url,
+            access_token,
+            max_len,
+        })
+    }
+
+    /// Call the API and return the response as JSON.
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: Option<&str>,
+        body: Vec<u8>,
+    ) -> Result<serde_json::Value, Error> {
+        let mut request = Request::builder();
+        request
+            .method(method)
+            .uri(format!("{}{}", self.base_url, path))
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", self.access_token),
+            );
+        if let Some(content_type) = content_type {
+            request.header(header::CONTENT_TYPE, content_type);
+        }
+        let request = request.body(Body::from(body)).map_err(|_| Error::BadUrl)?;
+
+        let response = self.client.request(request).compat().await?;
+        let status = response.status();
+        let body = response.into_body().compat().try_concat().await?;
+        tracing::debug!(%status, body = %String::from_utf8_lossy(&body), "mastodon response");
+
+        if !status.is_success() {
+            return Err(Error::BadStatus(status));
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Extract the ID in the response, which is encoded as a string.
+fn parse_id(value: &serde_json::Value) -> Result<u64, Error> {
+    value["id"]
+        .as_str()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| Error::InvalidResponse("missing id", Some(value.to_string())))
+}
+
+#[polyfuse::async_trait]
+impl TweetSink for MastodonSink {
+    fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    fn status_url(&self, id: u64) -> String {
+        format!("{}/web/statuses/{}", self.base_url, id)
+    }
+
+    fn supports_language(&self) -> bool {
+        true
+    }
+
+    fn supports_pin(&self) -> bool {
+        true
+    }
+
+    async fn pin(&self, _: &Credentials, id: u64) -> Result<(), Error> {
+        let path = format!("/api/v1/statuses/{}/pin", id);
+        self.request(Method::POST, &path, None, vec![]).await?;
+        Ok(())
+    }
+
+    async fn upload_media(
+        &self,
+        _: &Credentials,
+        data: &[u8],
+        media_type: mime::Mime,
+        alt_text: Option<&str>,
+    ) -> Result<u64, Error> {
+        let boundary = format!("tweet-fs-{:016x}", rand::random::<u64>());
+        let mut body = vec![];
+        if let Some(alt_text) = alt_text {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"description\"\r\n\r\n{}\r\n",
+                    boundary, alt_text
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"media\"\r\nContent-Type: {}\r\n\r\n",
+            boundary, media_type
+        ).as_bytes());
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        let mut value = self
+            .request(Method::POST, "/api/v2/media", Some(&content_type), body)
+            .await?;
+        let id = parse_id(&value)?;
+
+        // The large media (e.g. video) is processed asynchronously, and its URL
+        // is null until finished. The failed processing is reported as an error status.
+        let path = format!("/api/v1/media/{}", id);
+        while value["url"].is_null() {
+            tracing::debug!("the media {} is being processed", id);
+            tokio::time::delay_for(MEDIA_POLL_INTERVAL).await;
+            value = self.request(Method::GET, &path, None, vec![]).await?;
+        }
+        Ok(id)
+    }
+
+    async fn post(&self, _: &Credentials, opts: &PostOptions<'_>) -> Result<Response<u64>, Error> {
+        let form = {
+            let mut form = url::form_urlencoded::Serializer::new(String::new());
+            form.extend_pairs(status_params(opts));
+            form.finish()
+        };
+
+        let value = self
+            .request(
+                Method::POST,
+                "/api/v1/statuses",
+                Some("application/x-www-form-urlencoded"),
+                form.into_bytes(),
+            )
+            .await?;
+
+        Ok(Response {
+            rate_limit: -1,
+            rate_limit_remaining: i32::MAX,
+            rate_limit_reset: -1,
+            response: parse_id(&value)?,
+        })
+    }
+
+    async fn delete(&self, _: &Credentials, id: u64) -> Result<(), Error> {
+        let path = format!("/api/v1/statuses/{}", id);
+        self.request(Method::DELETE, &path, None, vec![]).await?;
+        Ok(())
+    }
+
+    async fn verify(&self, _: &Credentials) -> Result<Response<String>, Error> {
+        let value = self
+            .request(
+                Method::GET,
+                "/api/v1/accounts/verify_credentials",
+                None,
+                vec![],
+            )
+            .await?;
+        let acct = value["acct"]
+            .as_str()
+            .ok_or_else(|| Error::InvalidResponse("missing acct", Some(value.to_string())))?;
+        Ok(Response {
+            rate_limit: -1,
+            rate_limit_remaining: i32::MAX,
+            rate_limit_reset: -1,
+            response: acct.to_owned(),
+        })
+    }
+
+    async fn home_timeline(&self, _: &Credentials, count: usize) -> Result<Vec<Status>, Error> {
+        let path = format!("/api/v1/timelines/home?limit={}", count);
+        let value = self.request(Method::GET, &path, None, vec![]).await?;
+        let statuses = value
+            .as_array()
+            .ok_or_else(|| Error::InvalidResponse("expected an array", Some(value.to_string())))?;
+        statuses.iter().map(parse_status).collect()
+    }
+
+    async fn mentions(&self, _: &Credentials, count: usize) -> Result<Vec<Status>, Error> {
+        let path = format!("/api/v1/notifications?types[]=mention&limit={}", count);
+        let value = self.request(Method::GET, &path, None, vec![]).await?;
+        let notifications = value
+            .as_array()
+            .ok_or_else(|| Error::InvalidResponse("expected an array", Some(value.to_string())))?;
+        notifications
+            .iter()
+            .map(|notification| parse_status(&notification["status"]))
+            .collect()
+    }
+
+    async fn search(
+        &self,
+        _: &Credentials,
+        query: &str,
+        count: usize,
+    ) -> Result<Vec<Status>, Error> {
+        let params = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("q", query)
+            .append_pair("type", "statuses")
+            .append_pair("limit", &count.to_string())
+            .finish();
+        let path = format!("/api/v2/search?{}", params);
+        let value = self.request(Method::GET, &path, None, vec![]).await?;
+        let statuses = value["statuses"]
+            .as_array()
+            .ok_or_else(|| Error::InvalidResponse("missing statuses", Some(value.to_string())))?;
+        statuses.iter().map(parse_status).collect()
+    }
+
+    async fn user_id(&self, _: &Credentials, screen_name: &str) -> Result<u64, Error> {
+        let path = lookup_path(screen_name);
+        let value = self.request(Method::GET, &path, None, vec![]).await?;
+        parse_id(&value)
+    }
+
+    /// The direct message is posted as a status visible only to the mentioned user.
+    async fn send_dm(&self, _: &Credentials, recipient: u64, text: &str) -> Result<u64, Error> {
+        let path = format!("/api/v1/accounts/{}", recipient);
+        let account = self.request(Method::GET, &path, None, vec![]).await?;
+        let acct = account["acct"]
+            .as_str()
+            .ok_or_else(|| Error::InvalidResponse("missing acct", Some(account.to_string())))?;
+
+        let form = {
+            let mut form = url::form_urlencoded::Serializer::new(String::new());
+            form.append_pair("status", &format!("@{} {}", acct, text));
+            form.append_pair("visibility", "direct");
+            form.finish()
+        };
+        let value = self
+            .request(
+                Method::POST,
+                "/api/v1/statuses",
+                Some("application/x-www-form-urlencoded"),
+                form.into_bytes(),
+            )
+            .await?;
+        parse_id(&value)
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn like(&self, _: &Credentials, id: u64) -> Result<u64, Error> {
+        let path = format!("/api/v1/statuses/{}/favourite", id);
+        let value = self.request(Method::POST, &path, None, vec![]).await?;
+        Ok(value["favourites_count"].as_u64().unwrap_or_default())
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn unlike(&self, _: &Credentials, id: u64) -> Result<u64, Error> {
+        let path = format!("/api/v1/statuses/{}/unfavourite", id);
+        let value = self.request(Method::POST, &path, None, vec![]).await?;
+        Ok(value["favourites_count"].as_u64().unwrap_or_default())
+    }
+
+    #[cfg(feature = "reactions")]
+    async fn retweet(&self, _: &Credentials, id: u64) -> Result<u64, Error> {
+        let path = format!("/api/v1/statuses/{}/reblog", id);
+        let value = self.request(Method::POST, &path, None, vec![]).await?;
+        parse_id(&value)
+    }
+}
+
+/// Extract the status in the timeline or the search results.
+fn parse_status(status: &serde_json::Value) -> Result<Status, Error> {
+    Ok(Status {
+        id: parse_id(status)?,
+        screen_name: status["account"]["acct"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned(),
+        text: strip_html(status["content"].as_str().unwrap_or_default()),
+    })
+}
+
+/// Convert the HTML content of a status into the plain text.
+fn strip_html(html: &str) -> String {
+    let html = html
+        .replace("<br>", "\n")
+        .replace("<br />", "\n")
+        .replace("</p><p>", "\n\n");
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Return the path looking up the account, e.g. `user@example.com`.
+fn lookup_path(acct: &str) -> String {
+    let params = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("acct", acct)
+        .finish();
+    format!("/api/v1/accounts/lookup?{}", params)
+}
+
+/// Map the options onto the parameters of posting a status, ignoring the
+/// unsupported ones with a warning.
+fn status_params(opts: &PostOptions<'_>) -> Vec<(&'static str, String)> {
+    // Mastodon has no quotes, so the URL is appended to the status instead.
+    let status = match opts.quote {
+        Some(url) => format!("{} {}", opts.text, url),
+        None => opts.text.to_owned(),
+    };
+    if opts.coordinates.is_some() {
+        tracing::warn!("the coordinates are not supported by Mastodon");
+    }
+    if !opts.exclude_reply_user_ids.is_empty() {
+        tracing::warn!("excluding the reply users is not supported by Mastodon");
+    }
+
+    let mut params = vec![("status", status)];
+    if let Some(id) = opts.in_reply_to {
+        params.push(("in_reply_to_id", id.to_string()));
+    }
+    for id in opts.media_ids {
+        params.push(("media_ids[]", id.to_string()));
+    }
+    if opts.sensitive {
+        params.push(("sensitive", "true".to_owned()));
+    }
+    if let Some(cw) = opts.content_warning {
+        params.push(("spoiler_text", cw.to_owned()));
+    }
+    if let Some(language) = opts.language {
+        params.push(("language", language.to_owned()));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_only() {
+        let opts = PostOptions {
+            text: "Hello",
+            ..Default::default()
+        };
+        assert_eq!(status_params(&opts), vec![("status", "Hello".to_owned())]);
+    }
+
+    #[test]
+    fn all_supported_options() {
+        let opts = PostOptions {
+            text: "Hello",
+            in_reply_to: Some(42),
+            media_ids: &[1, 2],
+            sensitive: true,
+            content_warning: Some("spoiler"),
+            language: Some("ja"),
+            ..Default::default()
+        };
+        assert_eq!(
+            status_params(&opts),
+            vec![
+                ("status", "Hello".to_owned()),
+                ("in_reply_to_id", "42".to_owned()),
+                ("media_ids[]", "1".to_owned()),
+                ("media_ids[]", "2".to_owned()),
+                ("sensitive", "true".to_owned()),
+                ("spoiler_text", "spoiler".to_owned()),
+                ("language", "ja".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn quote_appended() {
+        let opts = PostOptions {
+            text: "Hello",
+            quote: Some("https://example.com/@user/1"),
+            ..Default::default()
+        };
+        assert_eq!(
+            status_params(&opts),
+            vec![("status", "Hello https://example.com/@user/1".to_owned())]
+        );
+    }
+
+    #[test]
+    fn unsupported_options_ignored() {
+        let opts = PostOptions {
+            text: "Hello",
+            exclude_reply_user_ids: &[1, 2],
+            coordinates: Some((35.0, 139.0)),
+            ..Default::default()
+        };
+        assert_eq!(status_params(&opts), vec![("status", "Hello".to_owned())]);
+    }
+
+    #[test]
+    fn lookup_path_encoded() {
+        assert_eq!(lookup_path("user"), "/api/v1/accounts/lookup?acct=user");
+        assert_eq!(
+            lookup_path("user@example.com"),
+            "/api/v1/accounts/lookup?acct=user%40example.com"
+        );
+        assert_eq!(
+            lookup_path("a&b=c#d"),
+            "/api/v1/accounts/lookup?acct=a%26b%3Dc%23d"
+        );
+    }
+}
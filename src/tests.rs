@@ -0,0 +1,715 @@
+//! The tests driving the filesystem by the raw FUSE requests as the kernel sends
+//! them, with the mock backend.
+
+use super::*;
+use polyfuse::{Session, SessionInitializer};
+
+mod media;
+mod text;
+
+// The opcodes and the flags of the kernel ABI, which are private in polyfuse.
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_OPEN: u32 = 14;
+const FUSE_WRITE: u32 = 16;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FSYNC: u32 = 20;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_GETATTR_FH: u32 = 1;
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_FH: u32 = 1 << 6;
+
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+
+/// The filesystem posting to the mock backend.
+struct Harness {
+    fs: Arc<TweetFS>,
+    session: Session,
+    statuses: Arc<std::sync::Mutex<Vec<String>>>,
+    unique: u64,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        Self::with_config(|_| ()).await
+    }
+
+    async fn with_config(f: impl FnOnce(&mut Config)) -> Self {
+        let (fs, statuses) = mock_fs(f);
+
+        let mut init = vec![];
+        for value in &[7, 29, 0, 0] {
+            init.extend_from_slice(&u32::to_ne_bytes(*value));
+        }
+        let input = request(FUSE_INIT, 1, 0, &init);
+        let mut output = vec![];
+        let session = SessionInitializer::default()
+            .try_init(&mut polyfuse::io::unite(&input[..], &mut output))
+            .await
+            .unwrap()
+            .expect("the session is not initialized");
+
+        Self {
+            fs,
+            session,
+            statuses,
+            unique: 1,
+        }
+    }
+
+    /// Send the request, returning the payload of the reply or the errno.
+    async fn call(&mut self, opcode: u32, ino: u64, arg: &[u8]) -> Result<Vec<u8>, i32> {
+        self.unique += 1;
+        let input = request(opcode, self.unique, ino, arg);
+        let mut output = vec![];
+        self.session
+            .process(&*self.fs, &mut polyfuse::io::unite(&input[..], &mut output))
+            .await
+            .unwrap();
+
+        assert_eq!(u64_at(&output, 8), self.unique, "unique");
+        match i32::from_ne_bytes([output[4], output[5], output[6], output[7]]) {
+            0 => Ok(output.split_off(OUT_HEADER_LEN)),
+            error => Err(-error),
+        }
+    }
+
+    async fn lookup(&mut self, name: &str) -> Result<u64, i32> {
+        self.lookup_in(ROOT_INO, name).await
+    }
+
+    async fn lookup_in(&mut self, parent: u64, name: &str) -> Result<u64, i32> {
+        let mut arg = name.as_bytes().to_vec();
+        arg.push(0);
+        let entry = self.call(FUSE_LOOKUP, parent, &arg).await?;
+        Ok(u64_at(&entry, 0))
+    }
+
+    /// Return the size of the file, as seen via the handle if given.
+    async fn getattr(&mut self, ino: u64, fh: Option<u64>) -> Result<u64, i32> {
+        let mut arg = vec![];
+        arg.extend_from_slice(&u32::to_ne_bytes(fh.map_or(0, |_| FUSE_GETATTR_FH)));
+        arg.extend_from_slice(&[0; 4]);
+        arg.extend_from_slice(&u64::to_ne_bytes(fh.unwrap_or(0)));
+        let attr = self.call(FUSE_GETATTR, ino, &arg).await?;
+        // The size follows the TTL and the inode number.
+        Ok(u64_at(&attr, 24))
+    }
+
+    /// Truncate the file via the handle, returning the size after truncated.
+    async fn truncate(&mut self, ino: u64, fh: u64, size: u64) -> Result<u64, i32> {
+        let mut arg = vec![];
+        arg.extend_from_slice(&u32::to_ne_bytes(FATTR_SIZE | FATTR_FH));
+        arg.extend_from_slice(&[0; 4]);
+        arg.extend_from_slice(&u64::to_ne_bytes(fh));
+        arg.extend_from_slice(&u64::to_ne_bytes(size));
+        // The other attributes are not set.
+        arg.extend_from_slice(&[0; 64]);
+        let attr = self.call(FUSE_SETATTR, ino, &arg).await?;
+        Ok(u64_at(&attr, 24))
+    }
+
+    async fn open(&mut self, ino: u64, flags: libc::c_int) -> Result<u64, i32> {
+        let mut arg = vec![];
+        arg.extend_from_slice(&u32::to_ne_bytes(flags as u32));
+        arg.extend_from_slice(&[0; 4]);
+        let open = self.call(FUSE_OPEN, ino, &arg).await?;
+        Ok(u64_at(&open, 0))
+    }
+
+    async fn write(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<u32, i32> {
+        let mut arg = vec![];
+        arg.extend_from_slice(&u64::to_ne_bytes(fh));
+        arg.extend_from_slice(&u64::to_ne_bytes(offset));
+        arg.extend_from_slice(&u32::to_ne_bytes(data.len() as u32));
+        arg.extend_from_slice(&[0; 20]);
+        arg.extend_from_slice(data);
+        let write = self.call(FUSE_WRITE, ino, &arg).await?;
+        Ok(u32::from_ne_bytes([write[0], write[1], write[2], write[3]]))
+    }
+
+    async fn flush(&mut self, ino: u64, fh: u64) -> Result<(), i32> {
+        let mut arg = vec![];
+        arg.extend_from_slice(&u64::to_ne_bytes(fh));
+        arg.extend_from_slice(&[0; 16]);
+        self.call(FUSE_FLUSH, ino, &arg).await.map(drop)
+    }
+
+    async fn fsync(&mut self, ino: u64, fh: u64) -> Result<(), i32> {
+        let mut arg = vec![];
+        arg.extend_from_slice(&u64::to_ne_bytes(fh));
+        arg.extend_from_slice(&[0; 8]);
+        self.call(FUSE_FSYNC, ino, &arg).await.map(drop)
+    }
+
+    async fn release(&mut self, ino: u64, fh: u64, flags: libc::c_int) -> Result<(), i32> {
+        let mut arg = vec![];
+        arg.extend_from_slice(&u64::to_ne_bytes(fh));
+        arg.extend_from_slice(&u32::to_ne_bytes(flags as u32));
+        arg.extend_from_slice(&[0; 12]);
+        self.call(FUSE_RELEASE, ino, &arg).await.map(drop)
+    }
+
+    /// Return the statuses posted to the mock backend so far.
+    fn statuses(&self) -> Vec<String> {
+        self.statuses.lock().unwrap().clone()
+    }
+}
+
+/// Create the filesystem posting to the mock backend with the config modified
+/// by `f`, along with the statuses posted to it.
+fn mock_fs(f: impl FnOnce(&mut Config)) -> (Arc<TweetFS>, Arc<std::sync::Mutex<Vec<String>>>) {
+    // The config file of the user is not read.
+    env::set_var("TWEET_FS_CONFIG", "/dev/null");
+    let mut config = Config::from_env().unwrap();
+    config.backend = Backend::Mock;
+    f(&mut config);
+
+    let sink = MockSink::default();
+    let statuses = sink.statuses();
+    (
+        TweetFS::with_sink(config, Box::new(sink)).unwrap(),
+        statuses,
+    )
+}
+
+/// Build the raw request with the header.
+fn request(opcode: u32, unique: u64, ino: u64, arg: &[u8]) -> Vec<u8> {
+    let mut request = vec![];
+    request.extend_from_slice(&u32::to_ne_bytes((IN_HEADER_LEN + arg.len()) as u32));
+    request.extend_from_slice(&u32::to_ne_bytes(opcode));
+    request.extend_from_slice(&u64::to_ne_bytes(unique));
+    request.extend_from_slice(&u64::to_ne_bytes(ino));
+    // uid, gid, pid and the padding.
+    request.extend_from_slice(&[0; 16]);
+    request.extend_from_slice(arg);
+    request
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_ne_bytes(buf)
+}
+
+#[tokio::test]
+async fn post_on_release() {
+    let mut harness = Harness::new().await;
+
+    let ino = harness.lookup("status").await.unwrap();
+    assert_eq!(ino, STATUS_INO);
+    let fh = harness.open(ino, libc::O_WRONLY).await.unwrap();
+    assert_eq!(harness.write(ino, fh, 0, b"Hello, world").await, Ok(12));
+    harness.flush(ino, fh).await.unwrap();
+    assert!(harness.statuses().is_empty(), "posted before release");
+
+    harness.release(ino, fh, libc::O_WRONLY).await.unwrap();
+    assert_eq!(harness.statuses(), vec!["Hello, world"]);
+}
+
+#[tokio::test]
+async fn lookup_unknown_entry() {
+    let mut harness = Harness::new().await;
+    assert_eq!(harness.lookup("unknown").await, Err(libc::ENOENT));
+}
+
+#[tokio::test]
+async fn append_concatenates() {
+    let mut harness = Harness::new().await;
+
+    // The kernel writes at the file size known via getattr on O_APPEND.
+    let flags = libc::O_WRONLY | libc::O_APPEND;
+    let fh = harness.open(STATUS_INO, flags).await.unwrap();
+    for chunk in &["Hello", ", ", "world"] {
+        let offset = harness.getattr(STATUS_INO, Some(fh)).await.unwrap();
+        let len = chunk.len() as u32;
+        assert_eq!(
+            harness
+                .write(STATUS_INO, fh, offset, chunk.as_bytes())
+                .await,
+            Ok(len)
+        );
+    }
+    harness.release(STATUS_INO, fh, flags).await.unwrap();
+
+    assert_eq!(harness.statuses(), vec!["Hello, world"]);
+}
+
+#[tokio::test]
+async fn stat_written_size() {
+    let mut harness = Harness::new().await;
+
+    let fh = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    assert_eq!(harness.getattr(STATUS_INO, Some(fh)).await, Ok(0));
+    harness.write(STATUS_INO, fh, 0, b"Hello").await.unwrap();
+    assert_eq!(harness.getattr(STATUS_INO, Some(fh)).await, Ok(5));
+    harness.write(STATUS_INO, fh, 5, b", world").await.unwrap();
+    assert_eq!(harness.getattr(STATUS_INO, Some(fh)).await, Ok(12));
+    // The stat without the handle reports the largest buffer of the inode.
+    assert_eq!(harness.getattr(STATUS_INO, None).await, Ok(12));
+
+    harness
+        .release(STATUS_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+    assert_eq!(harness.getattr(STATUS_INO, None).await, Ok(0));
+}
+
+#[tokio::test]
+async fn fsync_keeps_failed_status() {
+    let mut harness = Harness::with_config(|config| config.fsync_posts = true).await;
+
+    let fh = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(STATUS_INO, fh, 0, b"first").await.unwrap();
+    harness.fsync(STATUS_INO, fh).await.unwrap();
+    assert_eq!(harness.getattr(STATUS_INO, Some(fh)).await, Ok(5));
+
+    // The duplicated status is rejected, and kept in the buffer.
+    harness.write(STATUS_INO, fh, 5, b"first").await.unwrap();
+    assert_eq!(harness.fsync(STATUS_INO, fh).await, Err(libc::EALREADY));
+    assert_eq!(harness.getattr(STATUS_INO, Some(fh)).await, Ok(10));
+    harness.write(STATUS_INO, fh, 10, b" again").await.unwrap();
+    harness.fsync(STATUS_INO, fh).await.unwrap();
+
+    // The posted content cannot be rewritten.
+    assert_eq!(
+        harness.write(STATUS_INO, fh, 0, b"x").await,
+        Err(libc::EINVAL)
+    );
+    harness
+        .release(STATUS_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+
+    assert_eq!(harness.statuses(), vec!["first", "first again"]);
+}
+
+#[tokio::test]
+async fn post_once_on_flushes() {
+    let mut harness = Harness::new().await;
+
+    let fh = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(STATUS_INO, fh, 0, b"Hello").await.unwrap();
+    for _ in 0..3 {
+        harness.flush(STATUS_INO, fh).await.unwrap();
+    }
+    harness.write(STATUS_INO, fh, 5, b", world").await.unwrap();
+    harness.flush(STATUS_INO, fh).await.unwrap();
+    assert!(harness.statuses().is_empty(), "posted on flush");
+
+    harness
+        .release(STATUS_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+    assert_eq!(harness.statuses(), vec!["Hello, world"]);
+}
+
+#[tokio::test]
+async fn stale_handle() {
+    let mut harness = Harness::new().await;
+
+    let first = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    let second = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(STATUS_INO, first, 0, b"first").await.unwrap();
+    harness
+        .write(STATUS_INO, second, 0, b"second")
+        .await
+        .unwrap();
+    harness
+        .release(STATUS_INO, first, libc::O_WRONLY)
+        .await
+        .unwrap();
+    assert_eq!(
+        harness.release(STATUS_INO, first, libc::O_WRONLY).await,
+        Err(libc::EBADF)
+    );
+
+    // The slot of the released handle is reused by the next one.
+    let third = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    assert_eq!(third & 0xffff_ffff, first & 0xffff_ffff);
+    assert_ne!(third, first);
+    harness.write(STATUS_INO, third, 0, b"third").await.unwrap();
+    assert_eq!(
+        harness.write(STATUS_INO, first, 0, b"stale").await,
+        Err(libc::EBADF)
+    );
+    assert_eq!(
+        harness.release(STATUS_INO, first, libc::O_WRONLY).await,
+        Err(libc::EBADF)
+    );
+
+    harness
+        .release(STATUS_INO, second, libc::O_WRONLY)
+        .await
+        .unwrap();
+    harness
+        .release(STATUS_INO, third, libc::O_WRONLY)
+        .await
+        .unwrap();
+    assert_eq!(harness.statuses(), vec!["first", "second", "third"]);
+}
+
+#[tokio::test]
+async fn access_modes() {
+    use libc::{EACCES, EISDIR, ENOENT, EPERM};
+
+    let mut harness = Harness::new().await;
+
+    // The results of opening with O_RDONLY, O_WRONLY and O_RDWR.
+    #[cfg_attr(not(feature = "reactions"), allow(unused_mut))]
+    let mut cases = vec![
+        (STATUS_INO, [Ok(()), Ok(()), Ok(())]),
+        (DRAFT_INO, [Ok(()), Ok(()), Ok(())]),
+        (UNDO_INO, [Err(EPERM), Ok(()), Err(EPERM)]),
+        (MEDIA_INO, [Err(EPERM), Ok(()), Err(EPERM)]),
+        (MEDIA4_INO, [Err(EPERM), Ok(()), Err(EPERM)]),
+        (WHOAMI_INO, [Ok(()), Err(EACCES), Err(EACCES)]),
+        (STATS_INO, [Ok(()), Err(EACCES), Err(EACCES)]),
+        (RATELIMIT_INO, [Ok(()), Err(EACCES), Err(EACCES)]),
+        (LAST_ERROR_INO, [Ok(()), Err(EACCES), Err(EACCES)]),
+        (CONFIG_INO, [Ok(()), Err(EACCES), Err(EACCES)]),
+        (MENTIONS_INO, [Ok(()), Err(EACCES), Err(EACCES)]),
+        (TIMELINE_INO_BASE + 1, [Ok(()), Err(EACCES), Err(EACCES)]),
+        // Nothing has been read to be quoted yet.
+        (QUOTE_LAST_INO, [Err(ENOENT), Err(ENOENT), Err(ENOENT)]),
+        (ROOT_INO, [Err(EISDIR), Err(EISDIR), Err(EISDIR)]),
+        (TIMELINE_INO, [Err(EISDIR), Err(EISDIR), Err(EISDIR)]),
+        (0xdead, [Err(ENOENT), Err(ENOENT), Err(ENOENT)]),
+    ];
+    #[cfg(feature = "reactions")]
+    cases.push((LIKE_INO, [Ok(()), Ok(()), Err(EPERM)]));
+
+    for (ino, expected) in cases {
+        for (&flags, &expected) in [libc::O_RDONLY, libc::O_WRONLY, libc::O_RDWR]
+            .iter()
+            .zip(&expected)
+        {
+            let res = harness.open(ino, flags).await;
+            assert_eq!(
+                res.map(drop),
+                expected,
+                "ino = {}, flags = {:o}",
+                ino,
+                flags
+            );
+            // Nothing is written, so that nothing is posted on release.
+            if let Ok(fh) = res {
+                let _ = harness.release(ino, fh, flags).await;
+            }
+        }
+    }
+
+    // Nor the handle opened for reading writes to the read-only inode.
+    let fh = harness.open(WHOAMI_INO, libc::O_RDONLY).await.unwrap();
+    assert_eq!(
+        harness.write(WHOAMI_INO, fh, 0, b"x").await,
+        Err(libc::EBADF)
+    );
+    harness
+        .release(WHOAMI_INO, fh, libc::O_RDONLY)
+        .await
+        .unwrap();
+
+    assert!(harness.statuses().is_empty());
+}
+
+#[tokio::test]
+async fn media_kept_without_write() {
+    let mut harness = Harness::new().await;
+
+    let fh = harness.open(MEDIA_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(MEDIA_INO, fh, 0, b"media").await.unwrap();
+    harness
+        .release(MEDIA_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+
+    let fh = harness.open(MEDIA_INO, libc::O_WRONLY).await.unwrap();
+    harness
+        .release(MEDIA_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+    assert_eq!(harness.fs.media.lock().await[0], b"media");
+}
+
+#[tokio::test]
+async fn undo_on_written_content() {
+    let mut harness = Harness::new().await;
+
+    let fh = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(STATUS_INO, fh, 0, b"Hello").await.unwrap();
+    harness
+        .release(STATUS_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+    assert!(harness.fs.last_tweet.lock().await.is_some());
+
+    let fh = harness.open(UNDO_INO, libc::O_WRONLY).await.unwrap();
+    harness.release(UNDO_INO, fh, libc::O_WRONLY).await.unwrap();
+    assert!(
+        harness.fs.last_tweet.lock().await.is_some(),
+        "undone without write"
+    );
+
+    let fh = harness.open(UNDO_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(UNDO_INO, fh, 0, b"\n").await.unwrap();
+    harness.release(UNDO_INO, fh, libc::O_WRONLY).await.unwrap();
+    assert!(harness.fs.last_tweet.lock().await.is_none());
+}
+
+#[tokio::test]
+async fn post_gapped_buffer_on_exit() {
+    let mut harness = Harness::with_config(|config| config.post_on_exit = true).await;
+
+    let fh = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(STATUS_INO, fh, 5, b"world").await.unwrap();
+    harness.fs.shutdown().await;
+
+    // The NUL bytes in the gap are not posted.
+    assert!(harness.statuses().is_empty());
+}
+
+#[tokio::test]
+async fn post_quote_last_on_exit() {
+    let mut harness = Harness::with_config(|config| config.post_on_exit = true).await;
+    *harness.fs.last_read.lock().unwrap() = Some(1);
+
+    let fh = harness.open(QUOTE_LAST_INO, libc::O_WRONLY).await.unwrap();
+    harness
+        .write(QUOTE_LAST_INO, fh, 0, b"Hello")
+        .await
+        .unwrap();
+    harness.fs.shutdown().await;
+
+    assert_eq!(harness.statuses(), vec!["Hello"]);
+    // The quoted item is consumed as on release.
+    assert_eq!(*harness.fs.last_read.lock().unwrap(), None);
+}
+
+#[tokio::test]
+async fn truncate_after_fsync() {
+    let mut harness = Harness::with_config(|config| config.fsync_posts = true).await;
+
+    let fh = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(STATUS_INO, fh, 0, b"first").await.unwrap();
+    harness.fsync(STATUS_INO, fh).await.unwrap();
+
+    // The content posted by fsync cannot be truncated.
+    assert_eq!(harness.truncate(STATUS_INO, fh, 3).await, Err(libc::EINVAL));
+    assert_eq!(harness.getattr(STATUS_INO, Some(fh)).await, Ok(5));
+
+    // The size is relative to the posted content.
+    harness.write(STATUS_INO, fh, 5, b"second").await.unwrap();
+    assert_eq!(harness.truncate(STATUS_INO, fh, 8).await, Ok(8));
+    harness
+        .release(STATUS_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+
+    assert_eq!(harness.statuses(), vec!["first", "sec"]);
+}
+
+#[tokio::test]
+async fn append_to_draft() {
+    let mut harness = Harness::new().await;
+
+    let fh = harness.open(DRAFT_INO, libc::O_WRONLY).await.unwrap();
+    harness.write(DRAFT_INO, fh, 0, b"Hello").await.unwrap();
+    harness
+        .release(DRAFT_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+
+    let flags = libc::O_WRONLY | libc::O_APPEND;
+    let fh = harness.open(DRAFT_INO, flags).await.unwrap();
+    let offset = harness.getattr(DRAFT_INO, Some(fh)).await.unwrap();
+    assert_eq!(offset, 5);
+    harness
+        .write(DRAFT_INO, fh, offset, b", world")
+        .await
+        .unwrap();
+    harness.release(DRAFT_INO, fh, flags).await.unwrap();
+
+    assert_eq!(harness.getattr(DRAFT_INO, None).await, Ok(12));
+    assert_eq!(&*harness.fs.draft.lock().await, b"Hello, world");
+    assert!(harness.statuses().is_empty());
+}
+
+#[tokio::test]
+async fn stat_mentions_without_fetch() {
+    let mut harness = Harness::new().await;
+
+    let fh = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    harness
+        .write(STATUS_INO, fh, 0, b"Hi @alice")
+        .await
+        .unwrap();
+    harness
+        .release(STATUS_INO, fh, libc::O_WRONLY)
+        .await
+        .unwrap();
+
+    // Nothing is fetched until the mentions are read.
+    assert_eq!(harness.lookup("mentions").await, Ok(MENTIONS_INO));
+    assert_eq!(harness.getattr(MENTIONS_INO, None).await, Ok(0));
+
+    let mentions = harness.fs.mentions().await.unwrap();
+    assert_eq!(
+        harness.getattr(MENTIONS_INO, None).await,
+        Ok(mentions.len() as u64)
+    );
+}
+
+#[tokio::test]
+async fn count_scheduled_post() {
+    let harness = Harness::new().await;
+
+    let content = b"Hello".to_vec();
+    harness
+        .fs
+        .schedule(Utc::now(), STATUS_INO, content, Xattrs::default())
+        .await;
+    // The counter is updated after the status is posted.
+    for _ in 0..50 {
+        if harness.fs.posted.load(Ordering::Relaxed) > 0 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    assert_eq!(harness.fs.posted.load(Ordering::Relaxed), 1);
+    assert_eq!(harness.statuses(), vec!["Hello"]);
+}
+
+#[tokio::test]
+async fn check_spilled_length_on_release() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut harness = Harness::with_config(|config| {
+        config.spill_dir = Some(dir.path().to_owned());
+        config.spill_threshold = 4;
+    })
+    .await;
+
+    let fh = harness.open(STATUS_INO, libc::O_WRONLY).await.unwrap();
+    let head = "a".repeat(10);
+    harness
+        .write(STATUS_INO, fh, 0, head.as_bytes())
+        .await
+        .unwrap();
+    // The write to the spilled buffer is not checked.
+    let tail = "a".repeat(280);
+    harness
+        .write(STATUS_INO, fh, 10, tail.as_bytes())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        harness.release(STATUS_INO, fh, libc::O_WRONLY).await,
+        Err(libc::EFBIG)
+    );
+    assert!(harness.statuses().is_empty());
+}
+
+#[tokio::test]
+async fn lookup_past_search_cap() {
+    let mut harness = Harness::new().await;
+    harness
+        .fs
+        .search_queries
+        .lock()
+        .unwrap()
+        .extend((0..MAX_SEARCH_QUERIES).map(|i| format!("query{}", i)));
+
+    // The allocated one is still looked up.
+    assert_eq!(
+        harness.lookup_in(SEARCH_INO, "query0").await,
+        Ok(SEARCH_INO_BASE)
+    );
+
+    assert_eq!(
+        harness.lookup_in(SEARCH_INO, "another").await,
+        Err(libc::ENOSPC)
+    );
+}
+
+#[tokio::test]
+async fn lookup_past_dm_cap() {
+    let mut harness = Harness::new().await;
+    harness
+        .fs
+        .dm_recipients
+        .lock()
+        .unwrap()
+        .extend((0..MAX_DM_RECIPIENTS).map(|i| format!("user{}", i)));
+
+    // The allocated one is still looked up.
+    assert_eq!(harness.lookup_in(DM_INO, "user0").await, Ok(DM_INO_BASE));
+
+    assert_eq!(
+        harness.lookup_in(DM_INO, "another").await,
+        Err(libc::ENOSPC)
+    );
+}
+
+#[tokio::test]
+async fn bearer_only_rejects_user_context() {
+    env::set_var("BEARER_TOKEN", "bearer");
+    let mut harness = Harness::with_config(|config| config.backend = Backend::Twitter).await;
+
+    assert_eq!(harness.fs.whoami().await, Err(libc::EACCES));
+    assert_eq!(harness.fs.mentions().await, Err(libc::EACCES));
+    assert_eq!(
+        harness.lookup_in(TIMELINE_INO, "1").await,
+        Err(libc::EACCES)
+    );
+    assert_eq!(
+        harness.open(STATUS_INO, libc::O_WRONLY).await,
+        Err(libc::EROFS)
+    );
+}
+
+/// The tests mounting the filesystem actually, which require FUSE to be available.
+#[cfg(all(target_os = "linux", feature = "fuse-tests"))]
+mod mount {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn post_on_close() {
+        let (fs, statuses) = mock_fs(|_| ());
+        let mountpoint = tempfile::tempdir().unwrap();
+        let mut server = polyfuse_tokio::Server::mount(mountpoint.path(), &[])
+            .await
+            .unwrap();
+        let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+        let serve = tokio::spawn(async move {
+            server.run_until(fs, stopped).await.unwrap();
+            // The filesystem is unmounted when the server is dropped.
+            drop(server);
+        });
+
+        let path = mountpoint.path().join("status");
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+            file.write_all(b"Hello, world")
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        // The release is sent asynchronously after the file is closed.
+        for _ in 0..50 {
+            if !statuses.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*statuses.lock().unwrap(), vec!["Hello, world"]);
+
+        stop.send(()).unwrap();
+        serve.await.unwrap();
+    }
+}
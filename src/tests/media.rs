@@ -0,0 +1,47 @@
+//! The tests of detecting the media types of the staged media.
+
+use crate::*;
+
+/// Build the head of an ISO base media file with the major brand.
+fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+    let mut data = vec![0, 0, 0, 0x18];
+    data.extend_from_slice(b"ftyp");
+    data.extend_from_slice(brand);
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    data
+}
+
+#[test]
+fn sniff_images() {
+    let jpeg = [0xFF, 0xD8, 0xFF, 0xE0];
+    assert_eq!(sniff_media_type(&jpeg), Some(mime::IMAGE_JPEG));
+    let png = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+    assert_eq!(sniff_media_type(&png), Some(mime::IMAGE_PNG));
+    assert_eq!(sniff_media_type(b"GIF89a"), Some(mime::IMAGE_GIF));
+    assert_eq!(sniff_media_type(b"GIF87a"), Some(mime::IMAGE_GIF));
+    assert_eq!(
+        sniff_media_type(b"RIFF\0\0\0\0WEBPVP8 ").map(|m| m.to_string()),
+        Some("image/webp".to_owned())
+    );
+    assert_eq!(sniff_media_type(b"Hello, world"), None);
+    assert_eq!(sniff_media_type(b""), None);
+}
+
+#[test]
+fn sniff_mp4_brands() {
+    for brand in MP4_BRANDS {
+        assert_eq!(
+            sniff_media_type(&ftyp(brand)).map(|m| m.to_string()),
+            Some("video/mp4".to_owned()),
+            "{:?}",
+            std::str::from_utf8(*brand)
+        );
+    }
+
+    // HEIC, AVIF and QuickTime share the container, but are not MP4.
+    for brand in &[b"heic", b"heix", b"mif1", b"avif", b"qt  "] {
+        assert_eq!(sniff_media_type(&ftyp(brand)), None, "{:?}", brand);
+    }
+    // truncated before the brand
+    assert_eq!(sniff_media_type(b"\0\0\0\x18ftyp"), None);
+}
@@ -0,0 +1,226 @@
+//! The tests of counting the length of the statuses, splitting them into the
+//! threads and expanding the templates.
+
+use crate::*;
+
+#[test]
+fn char_weights() {
+    let cases = &[
+        ('a', 1),
+        ('é', 1),
+        // the end of the ranges counted as 1.
+        ('\u{10FF}', 1),
+        ('\u{1100}', 2),
+        ('\u{2000}', 1),
+        ('\u{200D}', 1),
+        ('\u{200E}', 2),
+        ('\u{2010}', 1),
+        ('\u{201F}', 1),
+        ('\u{2020}', 2),
+        ('\u{2032}', 1),
+        ('\u{2037}', 1),
+        ('\u{2038}', 2),
+        // CJK
+        ('あ', 2),
+        ('漢', 2),
+        ('한', 2),
+        // emoji
+        ('😀', 2),
+    ];
+    for &(c, weight) in cases {
+        assert_eq!(char_weight(c), weight, "{:?}", c);
+    }
+}
+
+#[test]
+fn weighted_lengths() {
+    let cases = &[
+        ("", 0),
+        ("Hello, world", 12),
+        ("café", 4),
+        ("‘quoted’", 8),
+        ("こんにちは", 10),
+        ("日本語 and English", 18),
+        ("😀", 2),
+        // the emoji with a skin tone modifier is 2 code points.
+        ("👍🏽", 4),
+        // the URLs are counted as 23 regardless of the length.
+        ("https://example.com/a/very/long/path/to/the/page", 23),
+        ("http://a.co", 23),
+        ("see www.example.com.", 28),
+        ("(https://example.com)", 25),
+        ("https://a.example https://b.example", 47),
+        ("日本 https://example.com", 28),
+    ];
+    for &(s, len) in cases {
+        assert_eq!(weighted_len(s), len, "{:?}", s);
+    }
+}
+
+#[test]
+fn weighted_limit() {
+    let limit = TwitterSink.max_len();
+    assert_eq!(limit, 280);
+
+    let fits = [
+        "a".repeat(280),
+        "あ".repeat(140),
+        format!("{}{}", "あ".repeat(139), "ab"),
+        format!("https://example.com {}", "a".repeat(256)),
+    ];
+    for s in &fits {
+        assert_eq!(CountMode::Weighted.len(s), 280, "{:?}", s);
+    }
+
+    let overflows = [
+        "a".repeat(281),
+        format!("{}a", "あ".repeat(140)),
+        format!("https://example.com {}", "a".repeat(257)),
+    ];
+    for s in &overflows {
+        assert_eq!(CountMode::Weighted.len(s), 281, "{:?}", s);
+    }
+}
+
+#[test]
+fn split_at_boundaries() {
+    let cases: &[(&str, usize, CountMode, &[&str])] = &[
+        // fitting in a tweet
+        ("Hello, world", 12, CountMode::Chars, &["Hello, world"]),
+        ("  Hello  ", 5, CountMode::Chars, &["Hello"]),
+        // word boundaries, including the one right after the limit
+        (
+            "hello world foo",
+            11,
+            CountMode::Chars,
+            &["hello world", "foo"],
+        ),
+        (
+            "hello world foo",
+            10,
+            CountMode::Chars,
+            &["hello", "world foo"],
+        ),
+        ("a  b  c", 1, CountMode::Chars, &["a", "b", "c"]),
+        // the sentence boundary is preferred
+        (
+            "One. Two three four",
+            15,
+            CountMode::Chars,
+            &["One.", "Two three four"],
+        ),
+        (
+            "One\ntwo three",
+            10,
+            CountMode::Chars,
+            &["One", "two three"],
+        ),
+        (
+            "今日は晴れ。明日は雨。",
+            8,
+            CountMode::Chars,
+            &["今日は晴れ。", "明日は雨。"],
+        ),
+        // the over-long words are split at the limit
+        ("abcdefghij", 4, CountMode::Chars, &["abcd", "efgh", "ij"]),
+        ("ab abcdefgh", 4, CountMode::Chars, &["ab", "abcd", "efgh"]),
+        // the multibyte characters are never split in the middle
+        ("ああ", 4, CountMode::Bytes, &["あ", "あ"]),
+        ("あいう", 5, CountMode::Weighted, &["あい", "う"]),
+        ("aあ", 2, CountMode::Weighted, &["a", "あ"]),
+        // at least one character is consumed
+        ("ab", 0, CountMode::Chars, &["a", "b"]),
+    ];
+    for &(status, limit, mode, expected) in cases {
+        assert_eq!(
+            split_thread(status, limit, mode),
+            expected,
+            "{:?} ({})",
+            status,
+            limit
+        );
+    }
+}
+
+#[test]
+fn split_points() {
+    assert_eq!(split_point("hello world", 8, CountMode::Chars), 5);
+    assert_eq!(split_point("hello world", 5, CountMode::Chars), 5);
+    assert_eq!(split_point("helloworld", 5, CountMode::Chars), 5);
+    assert_eq!(split_point("Hi. hello world", 12, CountMode::Chars), 3);
+    assert_eq!(split_point("あいう", 4, CountMode::Bytes), 3);
+    assert_eq!(split_point("あいう", 0, CountMode::Bytes), 3);
+}
+
+#[test]
+fn split_numbered_suffix() {
+    // Not numbered if fitting in a tweet.
+    assert_eq!(split_numbered("short", 10, CountMode::Chars), ["short"]);
+
+    assert_eq!(
+        split_numbered("aaaa bbbb cc", 10, CountMode::Chars),
+        ["aaaa (1/3)", "bbbb (2/3)", "cc (3/3)"]
+    );
+
+    // The suffix widened to 2 digits pushes the parts over the limit,
+    // and they are split again.
+    let status = "a b c d e f g h i j k l";
+    let parts = split_numbered(status, 8, CountMode::Chars);
+    assert_eq!(parts.len(), 12);
+    assert_eq!(parts[0], "a (1/12)");
+    assert_eq!(parts[11], "l (12/12)");
+
+    for &(status, mode) in &[
+        (status, CountMode::Chars),
+        (
+            "The quick brown fox jumps over the lazy dog.",
+            CountMode::Chars,
+        ),
+        (
+            "いろはにほへと ちりぬるを わかよたれそ つねならむ",
+            CountMode::Weighted,
+        ),
+    ] {
+        // The limits leave room for a character of weight 2 beside the suffix.
+        for limit in 10..40 {
+            for part in split_numbered(status, limit, mode) {
+                assert!(mode.len(&part) <= limit, "{:?} ({})", part, limit);
+            }
+        }
+    }
+}
+
+#[test]
+fn expand_templates() {
+    let now: DateTime<Utc> = "2020-05-17T09:08:07Z".parse().unwrap();
+    let cases = &[
+        ("Hello", "myhost", "Hello"),
+        (
+            "{{date}} {{time}} on {{host}}",
+            "myhost",
+            "2020-05-17 09:08:07 on myhost",
+        ),
+        ("{{date}}{{date}}", "myhost", "2020-05-172020-05-17"),
+        // unknown placeholders
+        ("{{unknown}}", "myhost", "{{unknown}}"),
+        ("{{}} {{ date }}", "myhost", "{{}} {{ date }}"),
+        ("{date} }}", "myhost", "{date} }}"),
+        // escaped braces
+        ("\\{{date}}", "myhost", "{{date}}"),
+        ("\\{{date}} {{date}}", "myhost", "{{date}} 2020-05-17"),
+        ("a\\{{", "myhost", "a{{"),
+        // unclosed braces
+        ("{{date", "myhost", "{{date"),
+        ("{{ {{date}}", "myhost", "{{ 2020-05-17"),
+        // missing values
+        ("from {{host}}", "", "from "),
+    ];
+    for &(template, host, expanded) in cases {
+        assert_eq!(
+            expand_template(template, &now, host),
+            expanded,
+            "{:?}",
+            template
+        );
+    }
+}